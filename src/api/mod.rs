@@ -1,7 +1,13 @@
+use crate::blockchain::validation::MAX_BLOCK_SIZE;
 use crate::blockchain::Blockchain;
+#[cfg(not(feature = "utxo"))]
+use crate::generator::Handle as GeneratorHandle;
+use crate::mempool::Mempool;
 use crate::miner::Handle as MinerHandle;
 use crate::network::message::Message;
 use crate::network::server::Handle as NetworkServerHandle;
+#[cfg(not(feature = "utxo"))]
+use crate::types::hash::H256;
 use serde::Serialize;
 
 use log::info;
@@ -16,8 +22,11 @@ use url::Url;
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
+    #[cfg(not(feature = "utxo"))]
+    generator: GeneratorHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
 }
 
 #[derive(Serialize)]
@@ -51,21 +60,29 @@ impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
+        #[cfg(not(feature = "utxo"))] generator: &GeneratorHandle,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
     ) {
-        let handle = HTTPServer::http(&addr).unwrap();
+        let handle = HTTPServer::http(addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
+            #[cfg(not(feature = "utxo"))]
+            generator: generator.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
+                #[cfg(not(feature = "utxo"))]
+                let generator = server.generator.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
+                let mempool = Arc::clone(&server.mempool);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -98,12 +115,67 @@ impl Server {
                                     return;
                                 }
                             };
-                            miner.start(lambda);
+                            match params.get("threads") {
+                                Some(threads) => {
+                                    let threads = match threads.parse::<usize>() {
+                                        Ok(v) if v > 0 => v,
+                                        Ok(_) => {
+                                            respond_result!(req, false, "threads must be positive");
+                                            return;
+                                        }
+                                        Err(e) => {
+                                            respond_result!(
+                                                req,
+                                                false,
+                                                format!("error parsing threads: {}", e)
+                                            );
+                                            return;
+                                        }
+                                    };
+                                    miner.start_with_threads(lambda, threads);
+                                }
+                                None => miner.start(lambda),
+                            }
                             respond_result!(req, true, "ok");
                         }
+                        #[cfg(not(feature = "utxo"))]
                         "/tx-generator/start" => {
-                            // unimplemented!()
-                            respond_result!(req, false, "unimplemented!");
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let theta = match params.get("theta") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing theta");
+                                    return;
+                                }
+                            };
+                            let theta = match theta.parse::<u64>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing theta: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            generator.start(theta);
+                            respond_result!(req, true, "ok");
+                        }
+                        #[cfg(not(feature = "utxo"))]
+                        "/tx-generator/pause" => {
+                            generator.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        #[cfg(not(feature = "utxo"))]
+                        "/tx-generator/stop" => {
+                            generator.stop();
+                            respond_result!(req, true, "ok");
+                        }
+                        #[cfg(feature = "utxo")]
+                        "/tx-generator/start" | "/tx-generator/pause" | "/tx-generator/stop" => {
+                            respond_result!(req, false, "unsupported in the UTXO model");
                         }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
@@ -117,12 +189,114 @@ impl Server {
                             respond_json!(req, v_string);
                         }
                         "/blockchain/longest-chain-tx" => {
-                            // unimplemented!()
-                            respond_result!(req, false, "unimplemented!");
+                            let blockchain = blockchain.lock().unwrap();
+                            let v = blockchain.all_tx_in_longest_chain();
+                            let v_string: Vec<Vec<String>> = v
+                                .into_iter()
+                                .map(|txs| txs.into_iter().map(|h| h.to_string()).collect())
+                                .collect();
+                            respond_json!(req, v_string);
+                        }
+                        #[cfg(not(feature = "utxo"))]
+                        "/blockchain/state" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let block = match params.get("block") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing block");
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            // a block hash has 64 hex digits, anything else is a height
+                            let hash = if block.len() == 64 {
+                                match block.parse::<H256>() {
+                                    Ok(v) => Some(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing block hash: {}", e)
+                                        );
+                                        return;
+                                    }
+                                }
+                            } else {
+                                match block.parse::<u64>() {
+                                    Ok(v) => blockchain.get_hash_at_height(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing block height: {}", e)
+                                        );
+                                        return;
+                                    }
+                                }
+                            };
+                            let state = match hash.and_then(|h| blockchain.state_at(&h)) {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "block not found");
+                                    return;
+                                }
+                            };
+                            let mut accounts = state.accounts();
+                            accounts.sort();
+                            let v_string: Vec<(String, u32, u64)> = accounts
+                                .into_iter()
+                                .map(|(address, nonce, balance)| {
+                                    (address.to_string(), nonce, balance)
+                                })
+                                .collect();
+                            respond_json!(req, v_string);
+                        }
+                        #[cfg(feature = "utxo")]
+                        "/blockchain/state" => {
+                            respond_result!(req, false, "unsupported in the UTXO model");
                         }
                         "/blockchain/longest-chain-tx-count" => {
-                            // unimplemented!()
-                            respond_result!(req, false, "unimplemented!");
+                            let blockchain = blockchain.lock().unwrap();
+                            let count: usize = blockchain
+                                .all_tx_in_longest_chain()
+                                .iter()
+                                .map(|txs| txs.len())
+                                .sum();
+                            respond_json!(req, count);
+                        }
+                        "/mempool/fee-estimate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blocks = match params.get("blocks") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing blocks");
+                                    return;
+                                }
+                            };
+                            let blocks = match blocks.parse::<u64>() {
+                                Ok(v) if v > 0 => v,
+                                Ok(_) => {
+                                    respond_result!(req, false, "blocks must be positive");
+                                    return;
+                                }
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing blocks: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            // the fee per byte to beat, so that this many blocks filled from
+                            // the current mempool would include the transaction
+                            let rate = mempool
+                                .lock()
+                                .unwrap()
+                                .estimate_fee_rate(blocks, MAX_BLOCK_SIZE);
+                            respond_json!(req, rate.as_f64());
                         }
                         _ => {
                             let content_type =
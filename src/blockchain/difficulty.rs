@@ -0,0 +1,101 @@
+use crate::types::hash::H256;
+use crate::types::u256::U256;
+
+/// Number of blocks between two difficulty adjustments
+pub const RETARGET_INTERVAL: u64 = 32;
+/// Intended time between two blocks, in milliseconds
+pub const TARGET_BLOCK_TIME: u128 = 10_000;
+/// Maximum factor by which the target changes in one adjustment, either way
+pub const MAX_ADJUSTMENT: u128 = 4;
+/// The easiest target, which every hash meets
+pub const MAX_TARGET: [u8; 32] = [255u8; 32];
+
+/// Scale a target by how long the last period took compared to `expected`, both in milliseconds,
+/// so that the next period takes about `expected`. The change is clamped to `MAX_ADJUSTMENT`
+/// either way, and the target never gets easier than `MAX_TARGET`. As in Bitcoin, the result is
+/// rounded down to what the compact encoding represents.
+pub fn retarget(target: &H256, actual: u128, expected: u128) -> H256 {
+    let actual = actual.clamp(expected / MAX_ADJUSTMENT, expected * MAX_ADJUSTMENT);
+    let (target, actual, expected) = (U256::from(target), U256::from(actual), U256::from(expected));
+    // target * actual / expected without overflowing in between, since with
+    // target = quotient * expected + remainder, only quotient * actual may be too large
+    let next = (target / expected)
+        .checked_mul(actual)
+        .and_then(|high| high.checked_add(target % expected * actual / expected));
+    let max = U256::from(H256::from(MAX_TARGET));
+    let next = next.map_or(max, |next| next.min(max));
+    U256::from_compact(next.to_compact()).unwrap().into()
+}
+
+/// The easiest target a block can have one adjustment after a block at `target`, i.e., `target`
+/// scaled by `MAX_ADJUSTMENT` before rounding, and never easier than `MAX_TARGET`.
+pub fn easiest_after(target: &H256) -> H256 {
+    let max = U256::from(H256::from(MAX_TARGET));
+    U256::from(target)
+        .checked_mul(U256::from(MAX_ADJUSTMENT))
+        .map_or(max, |easiest| easiest.min(max))
+        .into()
+}
+
+/// Expected number of hashes needed to meet `target`, i.e., 2^256 / (target + 1). Saturates for
+/// the zero target, which would need 2^256.
+pub fn work(target: &H256) -> U256 {
+    let target = U256::from(target);
+    match target.checked_add(U256::ONE) {
+        // 2^256 / divisor without overflowing, as (2^256 - divisor) / divisor + 1
+        Some(divisor) => (!target / divisor).saturating_add(U256::ONE),
+        None => U256::ONE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retarget_clamped() {
+        let target: H256 =
+            (hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
+        // twice as fast, twice as hard
+        assert_eq!(
+            retarget(&target, 500, 1000),
+            (hex!("00007fffff000000000000000000000000000000000000000000000000000000")).into()
+        );
+        // ten times as slow, only four times as easy
+        assert_eq!(
+            retarget(&target, 10_000, 1000),
+            (hex!("0003ffff00000000000000000000000000000000000000000000000000000000")).into()
+        );
+        assert_eq!(retarget(&target, 0, 1000), retarget(&target, 250, 1000));
+        // the maximum target does not overflow, and is rounded down
+        assert_eq!(
+            retarget(&MAX_TARGET.into(), 2000, 1000),
+            (hex!("ffff000000000000000000000000000000000000000000000000000000000000")).into()
+        );
+        assert_eq!(
+            retarget(&MAX_TARGET.into(), 500, 1000),
+            (hex!("7fffff0000000000000000000000000000000000000000000000000000000000")).into()
+        );
+    }
+
+    #[test]
+    fn easiest_after_one_adjustment() {
+        let target: H256 =
+            (hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
+        assert_eq!(
+            easiest_after(&target),
+            (hex!("0003fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffc")).into()
+        );
+        assert!(easiest_after(&target) >= retarget(&target, 10_000, 1000));
+        assert_eq!(easiest_after(&MAX_TARGET.into()), MAX_TARGET.into());
+    }
+
+    #[test]
+    fn work_of_target() {
+        assert_eq!(work(&MAX_TARGET.into()), U256::ONE);
+        assert_eq!(work(&H256::default()), U256::MAX);
+        let target: H256 =
+            (hex!("00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
+        assert_eq!(work(&target), U256::ONE << 32);
+    }
+}
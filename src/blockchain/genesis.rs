@@ -0,0 +1,206 @@
+use super::reward::RewardSchedule;
+use crate::state::State;
+use crate::types::address::Address;
+use crate::types::hash::{Hashable, H256};
+use crate::types::key_pair;
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The reason a genesis allocation file is rejected.
+#[derive(Debug)]
+pub enum AllocationError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// The address is not 40 hex digits
+    InvalidAddress(String),
+    /// The key has neither or both of an index and a seed, or the seed is not 64 hex digits
+    InvalidKey(String),
+}
+
+impl fmt::Display for AllocationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocationError::Io(e) => write!(f, "error reading allocation file: {}", e),
+            AllocationError::Json(e) => write!(f, "error parsing allocation file: {}", e),
+            AllocationError::InvalidAddress(a) => write!(f, "invalid address {}", a),
+            AllocationError::InvalidKey(k) => write!(f, "invalid key {}", k),
+        }
+    }
+}
+
+impl std::error::Error for AllocationError {}
+
+/// The genesis allocation file, e.g.,
+/// `{"balances": {"<address>": 1000}, "keys": [{"index": 0, "balance": 1000}]}`, where a key is
+/// derived either from an `index` with `key_pair::from_index` or from a hex `seed` with
+/// `key_pair::from_seed`. Every field is optional, including the block `reward` schedule.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AllocationFile {
+    #[serde(default)]
+    balances: HashMap<String, u64>,
+    #[serde(default)]
+    keys: Vec<KeyAllocation>,
+    #[serde(default)]
+    reward: RewardSchedule,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyAllocation {
+    #[serde(default)]
+    index: Option<u64>,
+    #[serde(default)]
+    seed: Option<String>,
+    balance: u64,
+}
+
+/// The initial coin offering: the balance of every address funded at genesis, and the seeds of
+/// the deterministic key pairs this node holds among them. It also sets how many coins the later
+/// blocks create.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    balances: BTreeMap<Address, u64>,
+    key_seeds: Vec<[u8; 32]>,
+    reward: RewardSchedule,
+}
+
+impl Allocation {
+    /// Fund the key pairs `key_pair::from_index(0..accounts)` with `balance` each.
+    pub fn with_indexed_keys(accounts: u64, balance: u64) -> Self {
+        let mut allocation = Self::default();
+        for index in 0..accounts {
+            allocation.add_key(key_pair::index_seed(index), balance);
+        }
+        allocation
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, AllocationError> {
+        let file: AllocationFile = serde_json::from_str(json).map_err(AllocationError::Json)?;
+        let mut allocation = Self {
+            reward: file.reward,
+            ..Self::default()
+        };
+        for (address, balance) in file.balances.iter() {
+            let mut bytes = [0u8; 20];
+            hex::decode_to_slice(address, &mut bytes)
+                .map_err(|_| AllocationError::InvalidAddress(address.clone()))?;
+            allocation.add(bytes.into(), *balance);
+        }
+        for key in file.keys.iter() {
+            let seed = match (key.index, &key.seed) {
+                (Some(index), None) => key_pair::index_seed(index),
+                (None, Some(seed)) => {
+                    let mut bytes = [0u8; 32];
+                    hex::decode_to_slice(seed, &mut bytes)
+                        .map_err(|_| AllocationError::InvalidKey(seed.clone()))?;
+                    bytes
+                }
+                _ => {
+                    return Err(AllocationError::InvalidKey(format!(
+                        "{:?}/{:?}",
+                        key.index, key.seed
+                    )))
+                }
+            };
+            allocation.add_key(seed, key.balance);
+        }
+        Ok(allocation)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, AllocationError> {
+        let json = fs::read_to_string(path).map_err(AllocationError::Io)?;
+        Self::from_json(&json)
+    }
+
+    fn add(&mut self, address: Address, balance: u64) {
+        let total = self.balances.entry(address).or_insert(0);
+        *total = total.saturating_add(balance);
+    }
+
+    fn add_key(&mut self, seed: [u8; 32], balance: u64) {
+        let key = key_pair::from_seed(&seed);
+        self.add(Address::from_public_key(*key.public_key()), balance);
+        self.key_seeds.push(seed);
+    }
+
+    pub fn reward(&self) -> RewardSchedule {
+        self.reward
+    }
+
+    /// The funded key pairs this node holds.
+    pub fn keys(&self) -> Vec<Ed25519KeyPair> {
+        self.key_seeds.iter().map(key_pair::from_seed).collect()
+    }
+
+    /// The ledger state at genesis.
+    pub fn state(&self) -> State {
+        let mut state = State::new();
+        for (address, balance) in self.balances.iter() {
+            state.credit(*address, *balance);
+        }
+        state
+    }
+}
+
+/// Commits to the funded balances and the reward schedule only, so that nodes holding different
+/// key pairs of the same allocation agree on the genesis block.
+impl Hashable for Allocation {
+    fn hash(&self) -> H256 {
+        let s = bincode::serialize(&(&self.balances, &self.reward)).unwrap();
+        digest::digest(&digest::SHA256, s.as_ref()).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_allocation_file() {
+        let key = key_pair::from_index(7);
+        let key_address = Address::from_public_key(*key.public_key());
+        let seeded = key_pair::from_seed(&[1u8; 32]);
+        let seeded_address = Address::from_public_key(*seeded.public_key());
+        let json = r#"{
+            "balances": {"00000000000000000000000000000000000000ff": 10},
+            "keys": [
+                {"index": 7, "balance": 20},
+                {"seed": "0101010101010101010101010101010101010101010101010101010101010101", "balance": 30}
+            ],
+            "reward": {"initial_subsidy": 8, "halving_interval": 2}
+        }"#;
+        let allocation = Allocation::from_json(json).unwrap();
+        let mut address = [0u8; 20];
+        address[19] = 0xff;
+        let mut expected = BTreeMap::new();
+        expected.insert(Address::from(address), 10);
+        expected.insert(key_address, 20);
+        expected.insert(seeded_address, 30);
+        assert_eq!(allocation.balances, expected);
+        assert_eq!(allocation.keys().len(), 2);
+        assert_eq!(allocation.reward().subsidy(2), 4);
+        assert_ne!(
+            allocation.hash(),
+            Allocation::from_json(
+                &json.replace("\"initial_subsidy\": 8", "\"initial_subsidy\": 9")
+            )
+            .unwrap()
+            .hash()
+        );
+        assert_eq!(Allocation::from_json("{}").unwrap(), Allocation::default());
+        assert!(matches!(
+            Allocation::from_json(r#"{"balances": {"00ff": 1}}"#),
+            Err(AllocationError::InvalidAddress(_))
+        ));
+        assert!(matches!(
+            Allocation::from_json(r#"{"keys": [{"balance": 1}]}"#),
+            Err(AllocationError::InvalidKey(_))
+        ));
+    }
+}
@@ -1,28 +1,327 @@
-use crate::types::block::Block;
-use crate::types::hash::H256;
+pub mod difficulty;
+pub mod genesis;
+pub mod orphan;
+pub mod reward;
+pub mod store;
+pub mod validation;
 
-pub struct Blockchain {}
+use crate::state::State;
+use crate::types::block::{Block, Content};
+use crate::types::hash::{Hashable, H256};
+use crate::types::u256::U256;
+use difficulty::{RETARGET_INTERVAL, TARGET_BLOCK_TIME};
+use genesis::Allocation;
+use log::{debug, error, info};
+use reward::RewardSchedule;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use store::BlockStore;
+use validation::BlockError;
+
+/// A block in the block tree, together with its position in it.
+struct Entry {
+    block: Block,
+    /// Number of blocks between this block and the genesis block, which has height 0.
+    height: u64,
+    /// Sum of the work of every block from the genesis block up to and including this one.
+    total_work: U256,
+    /// The ledger state after this block, so that a block on any fork is checked against the
+    /// state of its own parent without replaying the chain.
+    state: State,
+}
+
+pub struct Blockchain {
+    /// Every known block, on the longest chain or not, keyed by its hash.
+    blocks: HashMap<H256, Entry>,
+    genesis: H256,
+    tip: H256,
+    /// Where inserted blocks are persisted, if anywhere
+    store: Option<BlockStore>,
+    reward: RewardSchedule,
+}
+
+/// The genesis block. It only depends on the genesis allocation, whose hash takes the place of the
+/// parent, so that every node with the same allocation starts from the same block tree.
+pub fn genesis(allocation: &Allocation) -> Block {
+    Block::new(
+        allocation.hash(),
+        difficulty::MAX_TARGET.into(),
+        0,
+        Content::default(),
+    )
+}
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain, only containing the genesis block, without any funded account
     pub fn new() -> Self {
-        Self {}
+        Self::with_allocation(&Allocation::default())
+    }
+
+    /// Create a new blockchain, only containing the genesis block, whose ledger state is the
+    /// initial coin offering of `allocation`
+    pub fn with_allocation(allocation: &Allocation) -> Self {
+        let genesis = genesis(allocation);
+        let genesis_hash = genesis.hash();
+        let total_work = difficulty::work(&genesis.get_difficulty());
+        let mut blocks = HashMap::new();
+        blocks.insert(
+            genesis_hash,
+            Entry {
+                block: genesis,
+                height: 0,
+                total_work,
+                state: allocation.state(),
+            },
+        );
+        Self {
+            blocks,
+            genesis: genesis_hash,
+            tip: genesis_hash,
+            store: None,
+            reward: allocation.reward(),
+        }
+    }
+
+    /// Open the blockchain persisted in `dir`, rebuilding the block tree and the tip from the
+    /// stored blocks, and persist every block inserted from now on. The directory is created if
+    /// needed, and must have been used with the same allocation before.
+    pub fn open(allocation: &Allocation, dir: &Path) -> io::Result<Self> {
+        let (store, stored) = BlockStore::open(dir)?;
+        let mut blockchain = Self::with_allocation(allocation);
+        for (block, height) in stored.iter() {
+            let hash = block.hash();
+            // the blocks were fully checked before they were stored, but the state after each one
+            // is only known by applying it
+            let state = validation::check_block_in_chain(&blockchain, block).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid stored block {}: {}", hash, e),
+                )
+            })?;
+            blockchain.connect(block, hash, state);
+            if blockchain.blocks[&hash].height != *height {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("wrong height of stored block {}", hash),
+                ));
+            }
+        }
+        info!(
+            "Loaded {} blocks from {}, tip at height {}",
+            stored.len(),
+            dir.display(),
+            blockchain.blocks[&blockchain.tip].height
+        );
+        blockchain.store = Some(store);
+        Ok(blockchain)
+    }
+
+    /// Validate a block and insert it into blockchain. Returns whether the block is new, i.e., it
+    /// was not inserted before.
+    pub fn insert(&mut self, block: &Block) -> Result<bool, BlockError> {
+        if self.blocks.contains_key(&block.hash()) {
+            return Ok(false);
+        }
+        validation::check_block(block)?;
+        self.insert_checked(block)
+    }
+
+    /// Insert a block that already passed `validation::check_block` into blockchain, after
+    /// validating it against its parent only. Returns whether the block is new, i.e., it was not
+    /// inserted before.
+    pub fn insert_checked(&mut self, block: &Block) -> Result<bool, BlockError> {
+        let hash = block.hash();
+        if self.blocks.contains_key(&hash) {
+            return Ok(false);
+        }
+        let state = validation::check_block_in_chain(self, block)?;
+        self.connect(block, hash, state);
+        if let Some(store) = self.store.as_mut() {
+            // a failed append leaves at most a torn record at the end, which is truncated on the
+            // next start, but no descendant of the block can be stored after it
+            if let Err(e) = store.append(block, self.blocks[&hash].height) {
+                error!(
+                    "Error storing block {:?}, no longer storing blocks: {}",
+                    hash, e
+                );
+                self.store = None;
+            }
+        }
+        Ok(true)
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
-        unimplemented!()
+    /// Add a block whose parent is known to the block tree, together with the state after it, and
+    /// move the tip if needed.
+    fn connect(&mut self, block: &Block, hash: H256, state: State) {
+        let parent = &self.blocks[&block.get_parent()];
+        let height = parent.height + 1;
+        let total_work = parent
+            .total_work
+            .saturating_add(difficulty::work(&block.get_difficulty()));
+        self.blocks.insert(
+            hash,
+            Entry {
+                block: block.clone(),
+                height,
+                total_work,
+                state,
+            },
+        );
+        // a new tip only if its chain has strictly more work, so the first block seen wins ties
+        if total_work > self.blocks[&self.tip].total_work {
+            debug!("New tip {:?} at height {}", hash, height);
+            self.tip = hash;
+        }
     }
 
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
-        unimplemented!()
+        self.tip
+    }
+
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn get_block(&self, hash: &H256) -> Option<&Block> {
+        self.blocks.get(hash).map(|e| &e.block)
+    }
+
+    /// Get the height of a block, where the genesis block has height 0
+    pub fn get_height(&self, hash: &H256) -> Option<u64> {
+        self.blocks.get(hash).map(|e| e.height)
+    }
+
+    /// Get the difficulty a child of `parent` must have.
+    pub fn expected_difficulty(&self, parent: &H256) -> Option<H256> {
+        self.next_difficulty(self.get_block(parent)?)
+    }
+
+    /// Get the difficulty a child of `parent` must have, where `parent` need not be in the
+    /// blockchain yet as long as its own parent is. The difficulty is the parent's, except every
+    /// `RETARGET_INTERVAL` blocks, where it is scaled by how long the last period took. The first
+    /// period is skipped since the genesis timestamp is not a real time.
+    pub fn next_difficulty(&self, parent: &Block) -> Option<H256> {
+        let parent_hash = parent.hash();
+        let (mut hash, mut height) = match self.blocks.get(&parent_hash) {
+            Some(entry) => (parent_hash, entry.height),
+            None => {
+                let grandparent = parent.get_parent();
+                (grandparent, self.blocks.get(&grandparent)?.height)
+            }
+        };
+        let child_height = height + 1 + u64::from(hash != parent_hash);
+        if child_height % RETARGET_INTERVAL != 0 || child_height == RETARGET_INTERVAL {
+            return Some(parent.get_difficulty());
+        }
+        // the first block of the period, which is an ancestor of the parent
+        while height > child_height - RETARGET_INTERVAL {
+            hash = self.blocks[&hash].block.get_parent();
+            height -= 1;
+        }
+        let first = &self.blocks[&hash].block;
+        let actual = parent.get_timestamp().saturating_sub(first.get_timestamp());
+        let expected = u128::from(RETARGET_INTERVAL - 1) * TARGET_BLOCK_TIME;
+        Some(difficulty::retarget(
+            &parent.get_difficulty(),
+            actual,
+            expected,
+        ))
+    }
+
+    /// Get the block subsidy schedule, set by the genesis allocation
+    pub fn reward(&self) -> RewardSchedule {
+        self.reward
+    }
+
+    /// Get the accumulated proof-of-work of the chain ending at a block
+    pub fn get_total_work(&self, hash: &H256) -> Option<U256> {
+        self.blocks.get(hash).map(|e| e.total_work)
+    }
+
+    /// Get the ledger state after a block
+    pub fn state_at(&self, hash: &H256) -> Option<&State> {
+        self.blocks.get(hash).map(|e| &e.state)
+    }
+
+    /// Get the blocks to roll back and to apply when the tip moves from `from` to `to`. The first
+    /// vector goes from `from` down to, but excluding, the common ancestor; the second goes from
+    /// just after the common ancestor up to `to`.
+    pub fn reorg_path(&self, from: &H256, to: &H256) -> (Vec<H256>, Vec<H256>) {
+        let mut retracted = vec![];
+        let mut enacted = vec![];
+        let mut from = *from;
+        let mut to = *to;
+        while from != to {
+            let from_height = self.blocks[&from].height;
+            let to_height = self.blocks[&to].height;
+            if from_height >= to_height {
+                retracted.push(from);
+                from = self.blocks[&from].block.get_parent();
+            }
+            if to_height >= from_height {
+                enacted.push(to);
+                to = self.blocks[&to].block.get_parent();
+            }
+        }
+        enacted.reverse();
+        (retracted, enacted)
     }
 
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        // unimplemented!()
-        vec![]
+        let mut chain = vec![];
+        let mut hash = self.tip;
+        loop {
+            chain.push(hash);
+            if hash == self.genesis {
+                break;
+            }
+            hash = self.blocks[&hash].block.get_parent();
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Get the hash of the block at `height` in the longest chain
+    pub fn get_hash_at_height(&self, height: u64) -> Option<H256> {
+        let mut hash = self.tip;
+        let mut entry = &self.blocks[&hash];
+        if height > entry.height {
+            return None;
+        }
+        while entry.height > height {
+            hash = entry.block.get_parent();
+            entry = &self.blocks[&hash];
+        }
+        Some(hash)
+    }
+
+    /// Get the transaction hashes of every block in the longest chain, from the genesis block
+    pub fn all_tx_in_longest_chain(&self) -> Vec<Vec<H256>> {
+        self.all_blocks_in_longest_chain()
+            .iter()
+            .map(|hash| {
+                self.blocks[hash]
+                    .block
+                    .content
+                    .data
+                    .iter()
+                    .map(|tx| tx.hash())
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -39,9 +338,217 @@ mod tests {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.tip(), block.hash());
+    }
+
+    #[test]
+    fn switch_to_longer_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        let b1 = generate_random_block(&genesis_hash);
+        let b2 = generate_random_block(&b1.hash());
+        assert_eq!(blockchain.insert(&a1), Ok(true));
+        assert_eq!(blockchain.insert(&b1), Ok(true));
+        assert_eq!(blockchain.tip(), a1.hash());
+        assert_eq!(blockchain.insert(&b2), Ok(true));
+        assert_eq!(blockchain.tip(), b2.hash());
+        assert_eq!(
+            blockchain.all_blocks_in_longest_chain(),
+            vec![genesis_hash, b1.hash(), b2.hash()]
+        );
+        assert_eq!(blockchain.insert(&b2), Ok(false));
+    }
+
+    #[test]
+    fn heavier_fork_beats_longer_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut heavy = generate_random_block(&genesis_hash);
+        heavy.header.difficulty =
+            (hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
+        let light1 = generate_random_block(&genesis_hash);
+        let light2 = generate_random_block(&light1.hash());
+        blockchain.insert(&light1).unwrap();
+        blockchain.insert(&light2).unwrap();
+        // skip validation, the difficulty does not follow the static rule
+        blockchain.connect(&heavy, heavy.hash(), State::new());
+        assert_eq!(blockchain.tip(), heavy.hash());
+        assert_eq!(blockchain.get_height(&blockchain.tip()), Some(1));
+        assert_eq!(blockchain.get_hash_at_height(1), Some(heavy.hash()));
+        assert_eq!(blockchain.get_hash_at_height(0), Some(genesis_hash));
+        assert_eq!(blockchain.get_hash_at_height(2), None);
+        assert_eq!(
+            blockchain.get_total_work(&heavy.hash()),
+            Some(U256::from(1u64 + 16))
+        );
+    }
+
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    fn tx_in_longest_chain() {
+        use crate::types::block::generate_block_with;
+        use crate::types::transaction::generate_random_signed_transaction;
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let txs = vec![
+            generate_random_signed_transaction(),
+            generate_random_signed_transaction(),
+        ];
+        let hashes = txs.iter().map(|tx| tx.hash()).collect();
+        let a1 = generate_random_block(&genesis_hash);
+        let a2 = generate_block_with(&a1.hash(), txs.clone());
+        let b1 = generate_block_with(&genesis_hash, txs);
+        assert_eq!(blockchain.insert(&a1), Ok(true));
+        assert_eq!(blockchain.insert(&a2), Ok(true));
+        assert_eq!(blockchain.insert(&b1), Ok(true));
+        assert_eq!(
+            blockchain.all_tx_in_longest_chain(),
+            vec![vec![], vec![], hashes]
+        );
+    }
+
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    fn state_of_each_fork() {
+        use crate::types::block::generate_block_with;
+        use crate::types::transaction::generate_random_signed_transaction;
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let tx = generate_random_signed_transaction();
+        let sender = tx.transaction.sender;
+        let a1 = generate_block_with(&genesis_hash, vec![tx.clone()]);
+        let b1 = generate_block_with(&genesis_hash, vec![tx.clone()]);
+        let b2 = generate_block_with(&b1.hash(), vec![tx.clone()]);
+        assert_eq!(blockchain.insert(&a1), Ok(true));
+        // the same transaction applies once on each fork
+        assert_eq!(blockchain.insert(&b1), Ok(true));
+        assert!(blockchain.insert(&b2).is_err());
+        assert_eq!(
+            blockchain.state_at(&genesis_hash).unwrap().get(&sender).0,
+            0
+        );
+        assert_eq!(blockchain.state_at(&a1.hash()).unwrap().get(&sender).0, 1);
+        assert_eq!(blockchain.state_at(&b1.hash()).unwrap().get(&sender).0, 1);
+        assert_eq!(blockchain.state_at(&b2.hash()), None);
+    }
+
+    #[test]
+    fn reorg_path_to_other_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        let a2 = generate_random_block(&a1.hash());
+        let b2 = generate_random_block(&a1.hash());
+        let b3 = generate_random_block(&b2.hash());
+        for block in [&a1, &a2, &b2, &b3].iter() {
+            blockchain.insert(block).unwrap();
+        }
+        assert_eq!(
+            blockchain.reorg_path(&a2.hash(), &b3.hash()),
+            (vec![a2.hash()], vec![b2.hash(), b3.hash()])
+        );
+        assert_eq!(
+            blockchain.reorg_path(&genesis_hash, &a2.hash()),
+            (vec![], vec![a1.hash(), a2.hash()])
+        );
+    }
+
+    #[test]
+    fn reject_invalid_block() {
+        let mut blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.difficulty = H256::default();
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InsufficientWork));
+        assert!(!blockchain.contains(&block.hash()));
+    }
+
+    #[test]
+    fn reopen_from_store() {
+        let dir = store::TestDir::default();
+        let mut blockchain = Blockchain::open(&Allocation::default(), &dir.0).unwrap();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        let b1 = generate_random_block(&genesis_hash);
+        let b2 = generate_random_block(&b1.hash());
+        let a2 = generate_random_block(&a1.hash());
+        for block in [&a1, &b1, &b2, &a2].iter() {
+            blockchain.insert(block).unwrap();
+        }
+        drop(blockchain);
+        let blockchain = Blockchain::open(&Allocation::default(), &dir.0).unwrap();
+        // the first block seen still wins the tie
+        assert_eq!(blockchain.tip(), b2.hash());
+        assert!(blockchain.contains(&a2.hash()));
+        assert_eq!(blockchain.get_height(&a2.hash()), Some(2));
+        // the stored blocks do not descend from another genesis block
+        let other = Allocation::with_indexed_keys(1, 1);
+        assert!(Blockchain::open(&other, &dir.0).is_err());
+    }
+
+    #[test]
+    fn stop_storing_after_failed_append() {
+        let dir = store::TestDir::default();
+        let mut blockchain = Blockchain::open(&Allocation::default(), &dir.0).unwrap();
+        let b1 = generate_random_block(&blockchain.tip());
+        let b2 = generate_random_block(&b1.hash());
+        let b3 = generate_random_block(&b2.hash());
+        blockchain.insert(&b1).unwrap();
+        blockchain.store.as_mut().unwrap().fail_appends(&dir.0);
+        assert_eq!(blockchain.insert(&b2), Ok(true));
+        assert!(blockchain.store.is_none());
+        assert_eq!(blockchain.insert(&b3), Ok(true));
+        assert_eq!(blockchain.tip(), b3.hash());
+        drop(blockchain);
+        // the node still starts, from the blocks stored before the failure
+        let blockchain = Blockchain::open(&Allocation::default(), &dir.0).unwrap();
+        assert_eq!(blockchain.tip(), b1.hash());
+    }
+
+    #[test]
+    fn retarget_every_interval() {
+        let mut blockchain = Blockchain::new();
+        let max: H256 = difficulty::MAX_TARGET.into();
+        let mut parent = blockchain.tip();
+        // blocks come ten times too fast
+        for height in 1..2 * RETARGET_INTERVAL {
+            let block = Block::new(parent, max, height as u128 * 1000, Content::default());
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        let tip = blockchain.get_block(&parent).unwrap().clone();
+        // the first period starts at genesis and is not retargeted
+        let first = blockchain.get_hash_at_height(RETARGET_INTERVAL).unwrap();
+        assert_eq!(blockchain.get_block(&first).unwrap().get_difficulty(), max);
+        let expected = difficulty::retarget(&max, 1, 4);
+        assert_eq!(blockchain.expected_difficulty(&parent), Some(expected));
+
+        let mut block = Block::new(parent, max, tip.get_timestamp(), Content::default());
+        assert_eq!(
+            blockchain.insert(&block),
+            Err(BlockError::WrongDifficulty {
+                expected,
+                actual: max
+            })
+        );
+        block.header.difficulty = expected;
+        while block.hash() > expected {
+            block.header.nonce += 1;
+        }
+        assert_eq!(blockchain.next_difficulty(&block), Some(expected));
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
     }
+
+    #[test]
+    fn genesis_is_deterministic() {
+        assert_eq!(Blockchain::new().tip(), Blockchain::new().tip());
+        let allocation = Allocation::with_indexed_keys(2, 100);
+        let blockchain = Blockchain::with_allocation(&allocation);
+        assert_eq!(blockchain.tip(), genesis(&allocation).hash());
+        assert_ne!(blockchain.tip(), Blockchain::new().tip());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,154 @@
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Blocks whose parent is not in the blockchain yet, waiting for the parent to arrive.
+pub struct OrphanBuffer {
+    /// Every orphan keyed by its own hash
+    blocks: HashMap<H256, Block>,
+    /// Hashes of the orphans keyed by the hash of their missing parent
+    children: HashMap<H256, Vec<H256>>,
+    /// Hashes of the orphans in arrival order, together with their arrival time. Entries of
+    /// orphans that already left the buffer are skipped lazily.
+    arrivals: VecDeque<(H256, Instant)>,
+    max_orphans: usize,
+    max_age: Duration,
+}
+
+impl OrphanBuffer {
+    /// Create an orphan buffer holding at most `max_orphans` blocks, each for at most `max_age`.
+    pub fn new(max_orphans: usize, max_age: Duration) -> Self {
+        Self {
+            blocks: HashMap::new(),
+            children: HashMap::new(),
+            arrivals: VecDeque::new(),
+            max_orphans,
+            max_age,
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Buffer a block until its parent arrives. Expired orphans are dropped first, and the oldest
+    /// orphan makes room if the buffer is full. Returns whether the block is new to the buffer.
+    pub fn insert(&mut self, block: Block) -> bool {
+        let hash = block.hash();
+        if self.max_orphans == 0 || self.contains(&hash) {
+            return false;
+        }
+        self.expire();
+        while self.blocks.len() >= self.max_orphans {
+            let (oldest, _) = self.arrivals.pop_front().unwrap();
+            if self.remove(&oldest).is_some() {
+                debug!("Orphan buffer full, dropped orphan {:?}", oldest);
+            }
+        }
+        self.children
+            .entry(block.get_parent())
+            .or_default()
+            .push(hash);
+        self.blocks.insert(hash, block);
+        self.arrivals.push_back((hash, Instant::now()));
+        true
+    }
+
+    /// Remove and return the orphans whose parent is `parent`.
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        hashes
+            .iter()
+            .filter_map(|hash| self.blocks.remove(hash))
+            .collect()
+    }
+
+    /// Drop the orphans that have been waiting for longer than `max_age`.
+    pub fn expire(&mut self) {
+        while let Some((hash, arrival)) = self.arrivals.front().cloned() {
+            if self.blocks.contains_key(&hash) && arrival.elapsed() <= self.max_age {
+                break;
+            }
+            self.arrivals.pop_front();
+            if self.remove(&hash).is_some() {
+                debug!("Orphan {:?} expired", hash);
+            }
+        }
+    }
+
+    fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let block = self.blocks.remove(hash)?;
+        let parent = block.get_parent();
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|h| h != hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn take_children_of_parent() {
+        let mut buffer = OrphanBuffer::new(10, Duration::from_secs(60));
+        let parent = generate_random_hash();
+        let a = generate_random_block(&parent);
+        let b = generate_random_block(&parent);
+        let c = generate_random_block(&a.hash());
+        assert!(buffer.insert(a.clone()));
+        assert!(buffer.insert(b.clone()));
+        assert!(buffer.insert(c.clone()));
+        assert!(!buffer.insert(c.clone()));
+        let children: Vec<H256> = buffer
+            .take_children(&parent)
+            .iter()
+            .map(|b| b.hash())
+            .collect();
+        assert_eq!(children, vec![a.hash(), b.hash()]);
+        assert_eq!(buffer.len(), 1);
+        assert!(buffer.contains(&c.hash()));
+    }
+
+    #[test]
+    fn evict_oldest_when_full() {
+        let mut buffer = OrphanBuffer::new(2, Duration::from_secs(60));
+        let blocks: Vec<Block> = (0..3)
+            .map(|_| generate_random_block(&generate_random_hash()))
+            .collect();
+        for block in blocks.iter() {
+            buffer.insert(block.clone());
+        }
+        assert_eq!(buffer.len(), 2);
+        assert!(!buffer.contains(&blocks[0].hash()));
+        assert!(buffer.take_children(&blocks[0].get_parent()).is_empty());
+        assert!(buffer.contains(&blocks[2].hash()));
+    }
+
+    #[test]
+    fn expire_old_orphans() {
+        let mut buffer = OrphanBuffer::new(10, Duration::from_millis(0));
+        let block = generate_random_block(&generate_random_hash());
+        buffer.insert(block.clone());
+        std::thread::sleep(Duration::from_millis(5));
+        buffer.expire();
+        assert!(buffer.is_empty());
+        assert!(buffer.take_children(&block.get_parent()).is_empty());
+    }
+}
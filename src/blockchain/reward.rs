@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+/// Subsidy of the first blocks, before any halving
+pub const INITIAL_SUBSIDY: u64 = 50_000;
+/// Number of blocks between two halvings of the subsidy
+pub const HALVING_INTERVAL: u64 = 21_000;
+
+/// How many new coins a block may create on top of its fees, e.g.,
+/// `{"initial_subsidy": 50000, "halving_interval": 21000}` in the genesis allocation file. The
+/// subsidy halves every `halving_interval` blocks, or never if it is 0.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RewardSchedule {
+    pub initial_subsidy: u64,
+    pub halving_interval: u64,
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        Self {
+            initial_subsidy: INITIAL_SUBSIDY,
+            halving_interval: HALVING_INTERVAL,
+        }
+    }
+}
+
+impl RewardSchedule {
+    /// Get the subsidy of the block at `height`.
+    pub fn subsidy(&self, height: u64) -> u64 {
+        let halvings = height.checked_div(self.halving_interval).unwrap_or(0);
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_subsidy >> halvings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halving_schedule() {
+        let schedule = RewardSchedule {
+            initial_subsidy: 100,
+            halving_interval: 10,
+        };
+        assert_eq!(schedule.subsidy(1), 100);
+        assert_eq!(schedule.subsidy(9), 100);
+        assert_eq!(schedule.subsidy(10), 50);
+        assert_eq!(schedule.subsidy(25), 25);
+        assert_eq!(schedule.subsidy(70), 0);
+        assert_eq!(schedule.subsidy(u64::MAX), 0);
+        let constant = RewardSchedule {
+            halving_interval: 0,
+            ..schedule
+        };
+        assert_eq!(constant.subsidy(u64::MAX), 100);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "index.dat";
+/// Size of a serialized index record: two hashes, an offset, a length and a height
+const INDEX_RECORD_LEN: usize = 32 + 32 + 8 + 4 + 8;
+
+/// Where a block is in the block file, together with its position in the block tree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct IndexRecord {
+    hash: H256,
+    parent: H256,
+    offset: u64,
+    len: u32,
+    height: u64,
+}
+
+/// Append-only on-disk storage of the blocks inserted into the blockchain, in insertion order, so
+/// that the block tree can be rebuilt after a restart. The block file holds the serialized blocks
+/// back to back, and the index file one fixed-size record per block. A block is synced to disk
+/// before its index record is written, so after a crash only the last record can be torn.
+pub struct BlockStore {
+    blocks: File,
+    index: File,
+    /// Length of the block file, i.e., the offset of the next block
+    blocks_len: u64,
+}
+
+impl BlockStore {
+    /// Open the store in `dir`, creating it if needed, and return it together with the stored
+    /// blocks and their heights, in insertion order. A torn record at the end of either file is
+    /// truncated; any other inconsistency is an error.
+    pub fn open(dir: &Path) -> io::Result<(Self, Vec<(Block, u64)>)> {
+        fs::create_dir_all(dir)?;
+        let open = |name| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join(name))
+        };
+        let mut blocks = open(BLOCKS_FILE)?;
+        let mut index = open(INDEX_FILE)?;
+        let mut blocks_bytes = vec![];
+        blocks.read_to_end(&mut blocks_bytes)?;
+        let mut index_bytes = vec![];
+        index.read_to_end(&mut index_bytes)?;
+
+        let records = index_bytes.chunks(INDEX_RECORD_LEN);
+        let num_records = records.len();
+        let mut stored = vec![];
+        let mut blocks_len = 0;
+        for (i, bytes) in records.enumerate() {
+            match read_record(bytes, &blocks_bytes, blocks_len) {
+                Some((record, block)) => {
+                    blocks_len = record.offset + u64::from(record.len);
+                    stored.push((block, record.height));
+                }
+                None if i + 1 == num_records => {
+                    warn!("Truncating torn record at the end of the block index");
+                    break;
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupted block index record {}", i),
+                    ));
+                }
+            }
+        }
+        let index_len = (stored.len() * INDEX_RECORD_LEN) as u64;
+        if index_len < index_bytes.len() as u64 {
+            index.set_len(index_len)?;
+        }
+        if blocks_len < blocks_bytes.len() as u64 {
+            warn!("Truncating unindexed bytes at the end of the block file");
+            blocks.set_len(blocks_len)?;
+        }
+        let store = Self {
+            blocks,
+            index,
+            blocks_len,
+        };
+        Ok((store, stored))
+    }
+
+    /// Append a block whose parent is already stored.
+    pub fn append(&mut self, block: &Block, height: u64) -> io::Result<()> {
+        let bytes = bincode::serialize(block).unwrap();
+        let record = IndexRecord {
+            hash: block.hash(),
+            parent: block.get_parent(),
+            offset: self.blocks_len,
+            len: bytes.len() as u32,
+            height,
+        };
+        self.blocks.seek(SeekFrom::Start(self.blocks_len))?;
+        self.blocks.write_all(&bytes)?;
+        self.blocks.sync_data()?;
+        self.blocks_len += bytes.len() as u64;
+        self.index.seek(SeekFrom::End(0))?;
+        self.index
+            .write_all(&bincode::serialize(&record).unwrap())?;
+        self.index.sync_data()
+    }
+}
+
+/// Read an index record and its block, which must start at `offset`, the end of the previous
+/// block. Returns `None` if either is torn or does not match the other.
+fn read_record(bytes: &[u8], blocks_bytes: &[u8], offset: u64) -> Option<(IndexRecord, Block)> {
+    if bytes.len() != INDEX_RECORD_LEN {
+        return None;
+    }
+    let record: IndexRecord = bincode::deserialize(bytes).ok()?;
+    let end = record.offset.checked_add(u64::from(record.len))?;
+    if record.offset != offset || end > blocks_bytes.len() as u64 {
+        return None;
+    }
+    let block: Block =
+        bincode::deserialize(&blocks_bytes[record.offset as usize..end as usize]).ok()?;
+    if block.hash() != record.hash || block.get_parent() != record.parent {
+        return None;
+    }
+    Some((record, block))
+}
+
+/// A fresh directory for a test store, removed on drop.
+#[cfg(test)]
+pub struct TestDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl Default for TestDir {
+    fn default() -> Self {
+        use rand::Rng;
+        let name = format!("bitcoin-store-{:016x}", rand::thread_rng().gen::<u64>());
+        TestDir(std::env::temp_dir().join(name))
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+impl BlockStore {
+    /// Make every later append fail, as on a full disk, by reopening the block file in `dir`
+    /// read-only.
+    pub fn fail_appends(&mut self, dir: &Path) {
+        self.blocks = File::open(dir.join(BLOCKS_FILE)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    fn stored_hashes(stored: &[(Block, u64)]) -> Vec<H256> {
+        stored.iter().map(|(block, _)| block.hash()).collect()
+    }
+
+    #[test]
+    fn reopen_store() {
+        let dir = TestDir::default();
+        let a = generate_random_block(&generate_random_hash());
+        let b = generate_random_block(&a.hash());
+        let (mut store, stored) = BlockStore::open(&dir.0).unwrap();
+        assert!(stored.is_empty());
+        store.append(&a, 1).unwrap();
+        store.append(&b, 2).unwrap();
+        drop(store);
+        let (_, stored) = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(stored_hashes(&stored), vec![a.hash(), b.hash()]);
+        assert_eq!(stored[1].1, 2);
+        let record = IndexRecord {
+            hash: a.hash(),
+            parent: a.get_parent(),
+            offset: 0,
+            len: 0,
+            height: 0,
+        };
+        assert_eq!(bincode::serialize(&record).unwrap().len(), INDEX_RECORD_LEN);
+    }
+
+    #[test]
+    fn truncate_torn_records() {
+        let dir = TestDir::default();
+        let a = generate_random_block(&generate_random_hash());
+        let b = generate_random_block(&a.hash());
+        let c = generate_random_block(&b.hash());
+        let (mut store, _) = BlockStore::open(&dir.0).unwrap();
+        store.append(&a, 1).unwrap();
+        store.append(&b, 2).unwrap();
+        drop(store);
+        // a crash in the middle of appending c
+        let bytes = bincode::serialize(&c).unwrap();
+        let mut blocks = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join(BLOCKS_FILE))
+            .unwrap();
+        blocks.write_all(&bytes[..bytes.len() / 2]).unwrap();
+        let mut index = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join(INDEX_FILE))
+            .unwrap();
+        index.write_all(&[0u8; INDEX_RECORD_LEN / 2]).unwrap();
+
+        let (mut store, stored) = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(stored_hashes(&stored), vec![a.hash(), b.hash()]);
+        store.append(&c, 3).unwrap();
+        drop(store);
+        let (_, stored) = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(stored_hashes(&stored), vec![a.hash(), b.hash(), c.hash()]);
+    }
+}
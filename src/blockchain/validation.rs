@@ -0,0 +1,330 @@
+use super::difficulty;
+use super::Blockchain;
+use crate::state::{State, TransactionError};
+use crate::types::block::{current_timestamp, Block};
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::BlockTransaction;
+use std::fmt;
+
+/// How far a block's timestamp may be ahead of the local clock, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+/// Maximum total size in bytes of the serialized transactions of a block, besides the coinbase:
+/// about 200 transfers in the account model
+pub const MAX_BLOCK_SIZE: u64 = 32_000;
+
+/// The reason a block is rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// The parent is not in the blockchain, so the block cannot be checked or inserted yet
+    UnknownParent(H256),
+    /// The block hash does not meet the difficulty in the header
+    InsufficientWork,
+    /// The difficulty in the header is not the one expected at this height
+    WrongDifficulty { expected: H256, actual: H256 },
+    /// The parent is unknown and the difficulty in the header is easier than a child of the tip
+    /// could have after one adjustment
+    OrphanTooEasy { easiest: H256, actual: H256 },
+    /// The Merkle root in the header does not match the content
+    MerkleRootMismatch,
+    /// The timestamp is earlier than the parent's
+    TimestampTooEarly,
+    /// The timestamp is too far ahead of the local clock
+    TimestampTooLate,
+    /// The transactions besides the coinbase take this many bytes, more than `MAX_BLOCK_SIZE`
+    TooLarge(u64),
+    /// The signature of the transaction at this index does not verify, or is not by the sender
+    InvalidSignature(usize),
+    /// The transaction at this index is a coinbase, but not the first transaction
+    MisplacedCoinbase(usize),
+    /// The transaction at this index does not apply to the state after the parent
+    InvalidTransaction(usize, TransactionError),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::UnknownParent(parent) => write!(f, "unknown parent {}", parent),
+            BlockError::InsufficientWork => write!(f, "hash does not meet the difficulty"),
+            BlockError::WrongDifficulty { expected, actual } => {
+                write!(f, "difficulty {} is not the expected {}", actual, expected)
+            }
+            BlockError::OrphanTooEasy { easiest, actual } => {
+                write!(f, "orphan difficulty {} is easier than {}", actual, easiest)
+            }
+            BlockError::MerkleRootMismatch => write!(f, "Merkle root does not match the content"),
+            BlockError::TimestampTooEarly => write!(f, "timestamp is earlier than the parent's"),
+            BlockError::TimestampTooLate => write!(f, "timestamp is too far in the future"),
+            BlockError::TooLarge(size) => {
+                write!(
+                    f,
+                    "transactions take {} bytes, more than {}",
+                    size, MAX_BLOCK_SIZE
+                )
+            }
+            BlockError::InvalidSignature(i) => write!(f, "invalid signature of transaction {}", i),
+            BlockError::MisplacedCoinbase(i) => write!(f, "coinbase at position {}", i),
+            BlockError::InvalidTransaction(i, e) => write!(f, "invalid transaction {}: {}", i, e),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Check the parts of a block that do not depend on the blockchain: proof-of-work, Merkle root,
+/// timestamp against the local clock, size, transaction signatures and the coinbase position.
+/// Cheap enough to run on any block received from the network, including orphans.
+pub fn check_block(block: &Block) -> Result<(), BlockError> {
+    if block.hash() > block.get_difficulty() {
+        return Err(BlockError::InsufficientWork);
+    }
+    if block.get_timestamp() > current_timestamp() + MAX_FUTURE_BLOCK_TIME {
+        return Err(BlockError::TimestampTooLate);
+    }
+    if block.content.merkle_root() != block.header.merkle_root {
+        return Err(BlockError::MerkleRootMismatch);
+    }
+    let size = block
+        .content
+        .data
+        .iter()
+        .map(|tx| match tx {
+            BlockTransaction::Coinbase(_) => 0,
+            BlockTransaction::Signed(tx) => tx.size(),
+        })
+        .sum();
+    if size > MAX_BLOCK_SIZE {
+        return Err(BlockError::TooLarge(size));
+    }
+    for (i, tx) in block.content.data.iter().enumerate() {
+        match tx {
+            BlockTransaction::Coinbase(_) if i > 0 => return Err(BlockError::MisplacedCoinbase(i)),
+            BlockTransaction::Coinbase(_) => {}
+            BlockTransaction::Signed(tx) if !tx.verify() => {
+                return Err(BlockError::InvalidSignature(i))
+            }
+            BlockTransaction::Signed(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check an orphan before buffering it until its parent arrives: its difficulty cannot be checked
+/// yet, but it must not be easier than the one expected after the tip with the largest adjustment,
+/// so that `check_block` makes orphans cost about as much work as the blocks of the chain.
+pub fn check_orphan(blockchain: &Blockchain, block: &Block) -> Result<(), BlockError> {
+    let expected = blockchain.expected_difficulty(&blockchain.tip()).unwrap();
+    let easiest = difficulty::easiest_after(&expected);
+    if block.get_difficulty() > easiest {
+        return Err(BlockError::OrphanTooEasy {
+            easiest,
+            actual: block.get_difficulty(),
+        });
+    }
+    Ok(())
+}
+
+/// Check a block against its parent in the blockchain: the parent must be known, the difficulty
+/// must be the expected one, the timestamp must not go back in time, the transactions must apply
+/// to the state after the parent and the coinbase must not pay more than the subsidy plus the
+/// fees. Returns the state after the block.
+pub fn check_block_in_chain(blockchain: &Blockchain, block: &Block) -> Result<State, BlockError> {
+    let parent_hash = block.get_parent();
+    let parent = blockchain
+        .get_block(&parent_hash)
+        .ok_or(BlockError::UnknownParent(parent_hash))?;
+    let expected = blockchain.expected_difficulty(&parent_hash).unwrap();
+    if block.get_difficulty() != expected {
+        return Err(BlockError::WrongDifficulty {
+            expected,
+            actual: block.get_difficulty(),
+        });
+    }
+    if block.get_timestamp() < parent.get_timestamp() {
+        return Err(BlockError::TimestampTooEarly);
+    }
+    let height = blockchain.get_height(&parent_hash).unwrap() + 1;
+    blockchain
+        .state_at(&parent_hash)
+        .unwrap()
+        .apply_block(block, height, blockchain.reward().subsidy(height))
+        .map_err(|(i, e)| BlockError::InvalidTransaction(i, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::{
+        generate_random_signed_transaction, generate_random_transaction, SignedTransaction,
+    };
+
+    #[test]
+    fn reject_by_reason() {
+        let blockchain = Blockchain::new();
+        let block = generate_random_block(&blockchain.tip());
+        assert_eq!(check_block(&block), Ok(()));
+        assert_eq!(check_block_in_chain(&blockchain, &block), Ok(State::new()));
+
+        let mut hard = block.clone();
+        hard.header.difficulty = H256::default();
+        assert_eq!(check_block(&hard), Err(BlockError::InsufficientWork));
+
+        let mut unsigned = block.clone();
+        unsigned.content.data.push(
+            SignedTransaction {
+                transaction: generate_random_transaction(),
+                ..Default::default()
+            }
+            .into(),
+        );
+        assert_eq!(check_block(&unsigned), Err(BlockError::MerkleRootMismatch));
+        unsigned.header.merkle_root = unsigned.content.merkle_root();
+        assert_eq!(check_block(&unsigned), Err(BlockError::InvalidSignature(0)));
+
+        let tx = generate_random_signed_transaction();
+        let count = MAX_BLOCK_SIZE / tx.size() + 1;
+        let mut large = block.clone();
+        large.content.data = vec![tx.clone().into(); count as usize];
+        large.header.merkle_root = large.content.merkle_root();
+        assert_eq!(
+            check_block(&large),
+            Err(BlockError::TooLarge(count * tx.size()))
+        );
+        large.content.data.pop();
+        large.header.merkle_root = large.content.merkle_root();
+        assert_eq!(check_block(&large), Ok(()));
+
+        let mut late = block.clone();
+        late.header.timestamp = current_timestamp() + 2 * MAX_FUTURE_BLOCK_TIME;
+        assert_eq!(check_block(&late), Err(BlockError::TimestampTooLate));
+
+        let orphan = generate_random_block(&block.hash());
+        assert_eq!(
+            check_block_in_chain(&blockchain, &orphan),
+            Err(BlockError::UnknownParent(block.hash()))
+        );
+    }
+
+    #[test]
+    fn reject_bad_coinbase() {
+        use crate::types::address::generate_random_address;
+        use crate::types::transaction::Coinbase;
+        let blockchain = Blockchain::new();
+        let subsidy = blockchain.reward().subsidy(1);
+        let coinbase = Coinbase {
+            height: 1,
+            receiver: generate_random_address(),
+            value: subsidy,
+        };
+        let with_coinbase = |coinbase: &Coinbase| {
+            let mut block = generate_random_block(&blockchain.tip());
+            block.content.data = vec![BlockTransaction::Coinbase(coinbase.clone())];
+            block.header.merkle_root = block.content.merkle_root();
+            block
+        };
+        let block = with_coinbase(&coinbase);
+        assert_eq!(check_block(&block), Ok(()));
+        let state = check_block_in_chain(&blockchain, &block).unwrap();
+        #[cfg(not(feature = "utxo"))]
+        assert_eq!(state.get(&coinbase.receiver).1, subsidy);
+        #[cfg(feature = "utxo")]
+        assert_eq!(state.balance(&coinbase.receiver), subsidy);
+
+        let mut misplaced = block.clone();
+        misplaced
+            .content
+            .data
+            .insert(0, generate_random_signed_transaction().into());
+        misplaced.header.merkle_root = misplaced.content.merkle_root();
+        assert_eq!(
+            check_block(&misplaced),
+            Err(BlockError::MisplacedCoinbase(1))
+        );
+        let overpaying = Coinbase {
+            value: subsidy + 1,
+            ..coinbase.clone()
+        };
+        assert_eq!(
+            check_block_in_chain(&blockchain, &with_coinbase(&overpaying)),
+            Err(BlockError::InvalidTransaction(
+                0,
+                TransactionError::CoinbaseOverpays {
+                    allowed: subsidy,
+                    value: subsidy + 1
+                }
+            ))
+        );
+        let replayed = Coinbase {
+            height: 2,
+            ..coinbase
+        };
+        assert_eq!(
+            check_block_in_chain(&blockchain, &with_coinbase(&replayed)),
+            Err(BlockError::InvalidTransaction(
+                0,
+                TransactionError::WrongCoinbaseHeight {
+                    expected: 1,
+                    actual: 2
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn reject_easy_orphan() {
+        use crate::types::block::Content;
+        use difficulty::{MAX_TARGET, RETARGET_INTERVAL};
+        let mut blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        // blocks come ten times too fast, so the target gets four times harder twice
+        let mut target: H256 = MAX_TARGET.into();
+        for height in 1..3 * RETARGET_INTERVAL {
+            target = blockchain.expected_difficulty(&parent).unwrap();
+            let mut block = Block::new(parent, target, height as u128 * 1000, Content::default());
+            while block.hash() > target {
+                block.header.nonce += 1;
+            }
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        let next = blockchain.expected_difficulty(&parent).unwrap();
+        assert!(next < target);
+
+        let mut orphan = generate_random_block(&generate_random_block(&parent).hash());
+        assert_eq!(check_block(&orphan), Ok(()));
+        let easiest = match check_orphan(&blockchain, &orphan) {
+            Err(BlockError::OrphanTooEasy { easiest, actual }) => {
+                assert_eq!(actual, MAX_TARGET.into());
+                easiest
+            }
+            e => panic!("{:?}", e),
+        };
+        // one adjustment away from the next difficulty, but not two
+        assert_eq!(easiest, difficulty::easiest_after(&next));
+        assert!(easiest > next && easiest < MAX_TARGET.into());
+        orphan.header.difficulty = easiest;
+        assert_eq!(check_orphan(&blockchain, &orphan), Ok(()));
+    }
+
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    fn reject_replay() {
+        let blockchain = Blockchain::new();
+        let block = generate_random_block(&blockchain.tip());
+        let mut replay = block.clone();
+        let tx = generate_random_signed_transaction();
+        replay.content.data = vec![tx.clone().into(), tx.into()];
+        replay.header.merkle_root = replay.content.merkle_root();
+        assert_eq!(check_block(&replay), Ok(()));
+        assert_eq!(
+            check_block_in_chain(&blockchain, &replay),
+            Err(BlockError::InvalidTransaction(
+                1,
+                TransactionError::WrongNonce {
+                    expected: 2,
+                    actual: 1
+                }
+            ))
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use log::{debug, info};
+
+use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use rand::Rng;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::miner::Handle as MinerHandle;
+use crate::network::message::Message;
+use crate::network::server::Handle as ServerHandle;
+use crate::types::address::Address;
+use crate::types::hash::Hashable;
+use crate::types::transaction::{sign, SignedTransaction, Transaction};
+
+/// Maximum value of a generated transfer
+const MAX_VALUE: u32 = 100;
+/// Maximum fee of a generated transfer
+const MAX_FEE: u32 = 10;
+
+enum ControlSignal {
+    /// Generate a transaction every `theta` microseconds
+    Start(u64),
+    Pause,
+    /// Pause. A restart continues after the pending transactions of every account, as after a
+    /// pause
+    Stop,
+}
+
+enum OperatingState {
+    Paused,
+    Run(u64),
+}
+
+pub struct Context {
+    /// Channel for receiving control signal
+    control_chan: Receiver<ControlSignal>,
+    operating_state: OperatingState,
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
+    /// The key pairs of the funded accounts to send from
+    keys: Vec<Ed25519KeyPair>,
+    addresses: Vec<Address>,
+    /// Index of the next account to send from, in turn
+    next_sender: usize,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    /// Channel for sending signal to the generator thread
+    control_chan: Sender<ControlSignal>,
+}
+
+/// Create a transaction generator sending between the accounts of `keys`, which should be funded.
+pub fn new(
+    server: &ServerHandle,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    miner: &MinerHandle,
+    keys: Vec<Ed25519KeyPair>,
+) -> (Context, Handle) {
+    let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let addresses = keys
+        .iter()
+        .map(|key| Address::from_public_key(*key.public_key()))
+        .collect();
+
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        operating_state: OperatingState::Paused,
+        server: server.clone(),
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        miner: miner.clone(),
+        keys,
+        addresses,
+        next_sender: 0,
+    };
+
+    let handle = Handle {
+        control_chan: signal_chan_sender,
+    };
+
+    (ctx, handle)
+}
+
+impl Handle {
+    pub fn stop(&self) {
+        self.control_chan.send(ControlSignal::Stop).unwrap();
+    }
+
+    pub fn start(&self, theta: u64) {
+        self.control_chan.send(ControlSignal::Start(theta)).unwrap();
+    }
+
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+}
+
+impl Context {
+    pub fn start(mut self) {
+        thread::Builder::new()
+            .name("tx-generator".to_string())
+            .spawn(move || {
+                self.generator_loop();
+            })
+            .unwrap();
+        info!("Transaction generator initialized into paused mode");
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Stop => {
+                info!("Transaction generator stopped");
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Start(theta) => {
+                info!("Transaction generator starting with theta {}", theta);
+                self.operating_state = OperatingState::Run(theta);
+            }
+            ControlSignal::Pause => {
+                info!("Transaction generator paused");
+                self.operating_state = OperatingState::Paused;
+            }
+        }
+    }
+
+    /// Create a signed transfer from the next account in turn to a random account, with the next
+    /// account nonce of the sender and a random fee. Returns `None` if the sender cannot afford it.
+    fn transfer(&mut self) -> Option<SignedTransaction> {
+        let i = self.next_sender;
+        self.next_sender = (i + 1) % self.keys.len();
+        let sender = self.addresses[i];
+        let (nonce, balance) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            blockchain.state_at(&blockchain.tip()).unwrap().get(&sender)
+        };
+        // continue after the pending transactions of the sender, so that none of them is signed
+        // again with a conflicting one
+        let nonce = match self.mempool.lock().unwrap().pending_nonce(&sender) {
+            Some(pending) if pending > nonce => pending,
+            _ => nonce,
+        };
+        let mut rng = rand::thread_rng();
+        let value = rng.gen_range(1..=MAX_VALUE);
+        let fee = rng.gen_range(0..=MAX_FEE);
+        if balance < u64::from(value) + u64::from(fee) {
+            return None;
+        }
+        let transaction = Transaction {
+            sender,
+            receiver: self.addresses[rng.gen_range(0..self.addresses.len())],
+            value,
+            fee,
+            account_nonce: nonce.wrapping_add(1),
+        };
+        let signature = sign(&transaction, &self.keys[i]);
+        Some(SignedTransaction {
+            transaction,
+            signature: signature.as_ref().to_vec(),
+            public_key: self.keys[i].public_key().as_ref().to_vec(),
+        })
+    }
+
+    fn generator_loop(&mut self) {
+        loop {
+            // check and react to control signals
+            match self.operating_state {
+                OperatingState::Paused => {
+                    let signal = self.control_chan.recv().unwrap();
+                    self.handle_signal(signal);
+                    continue;
+                }
+                _ => match self.control_chan.try_recv() {
+                    Ok(signal) => {
+                        self.handle_signal(signal);
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => panic!("Generator control channel detached"),
+                },
+            }
+            if let OperatingState::Run(theta) = self.operating_state {
+                if !self.keys.is_empty() {
+                    if let Some(tx) = self.transfer() {
+                        let hash = tx.hash();
+                        let blockchain = self.blockchain.lock().unwrap();
+                        let state = blockchain.state_at(&blockchain.tip()).unwrap();
+                        let inserted = self.mempool.lock().unwrap().insert(tx, state);
+                        drop(blockchain);
+                        if inserted {
+                            debug!("Generated transaction {:?}", hash);
+                            self.miner.update();
+                            self.server
+                                .broadcast(Message::NewTransactionHashes(vec![hash]));
+                        }
+                    }
+                }
+                if theta != 0 {
+                    let interval = time::Duration::from_micros(theta);
+                    thread::sleep(interval);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::Allocation;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(60000)]
+    fn generate_valid_transfers() {
+        let allocation = Allocation::with_indexed_keys(2, 1000);
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_allocation(&allocation)));
+        let mempool = Arc::new(Mutex::new(Mempool::new(100)));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (miner_ctx, miner, _finished_block_chan) =
+            crate::miner::new(&blockchain, &mempool, 1, None);
+        // the miner stays paused, it only consumes the update signals
+        miner_ctx.start();
+        let (generator_ctx, generator) =
+            new(&server, &blockchain, &mempool, &miner, allocation.keys());
+        generator_ctx.start();
+        generator.start(0);
+        for _ in 0..6 {
+            match server_receiver.recv() {
+                Some(Message::NewTransactionHashes(_)) => {}
+                _ => panic!(),
+            }
+        }
+        generator.pause();
+        // every generated transaction applies in order, with consecutive account nonces
+        let mut state = allocation.state();
+        for tx in mempool.lock().unwrap().take(6) {
+            assert!(tx.verify());
+            state.apply_transaction(&tx).unwrap();
+        }
+        generator.stop();
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn restart_after_stop() {
+        let allocation = Allocation::with_indexed_keys(1, 1000);
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_allocation(&allocation)));
+        let mempool = Arc::new(Mutex::new(Mempool::new(1000)));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (miner_ctx, miner, _finished_block_chan) =
+            crate::miner::new(&blockchain, &mempool, 1, None);
+        miner_ctx.start();
+        let (generator_ctx, generator) =
+            new(&server, &blockchain, &mempool, &miner, allocation.keys());
+        generator_ctx.start();
+        generator.start(1000);
+        assert!(matches!(
+            server_receiver.recv(),
+            Some(Message::NewTransactionHashes(_))
+        ));
+        generator.stop();
+        // the generator thread keeps running after a stop, so it can be controlled again
+        thread::sleep(time::Duration::from_millis(100));
+        let stopped: Vec<SignedTransaction> = mempool.lock().unwrap().take(1000);
+        generator.start(1000);
+        while mempool.lock().unwrap().len() == stopped.len() {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        generator.stop();
+        // the restart continued after the pending transactions, instead of replacing them with
+        // conflicting ones
+        let mempool = mempool.lock().unwrap();
+        assert!(stopped.iter().all(|tx| mempool.contains(&tx.hash())));
+    }
+}
@@ -4,20 +4,33 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+#[cfg(not(feature = "utxo"))]
+pub mod generator;
+pub mod mempool;
 pub mod miner;
 pub mod network;
+pub mod state;
 pub mod types;
 
 use api::Server as ApiServer;
+use blockchain::genesis::Allocation;
 use blockchain::Blockchain;
 use clap::clap_app;
 use log::{error, info};
+use mempool::Mempool;
 use smol::channel;
 use std::net;
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
+use types::address::Address;
+
+/// Number of accounts funded at genesis without an allocation file, with the key pairs
+/// `key_pair::from_index(0..)`
+const ICO_ACCOUNTS: u64 = 100;
+/// Balance of each account funded at genesis without an allocation file
+const ICO_BALANCE: u64 = 1_000_000;
 
 fn main() {
     // parse command line arguments
@@ -29,14 +42,42 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg mempool_size: --("mempool-size") [INT] default_value("10000") "Sets the maximum number of pending transactions in the mempool")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of hashing threads for the miner")
+     (@arg genesis: --genesis [FILE] "Sets the genesis allocation file, funding the default test accounts if absent")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory to persist the blockchain in, keeping it in memory only if absent")
+     (@arg coinbase_address: --("coinbase-address") [ADDR] "Sets the address the mined block rewards are paid to, mining blocks without a coinbase if absent")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
+    // initial coin offering, every node with the same allocation starts from the same genesis
+    let allocation = match matches.value_of("genesis") {
+        Some(path) => Allocation::load(path).unwrap_or_else(|e| {
+            error!("Error loading genesis allocation {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => Allocation::with_indexed_keys(ICO_ACCOUNTS, ICO_BALANCE),
+    };
+    let blockchain = match matches.value_of("data_dir") {
+        Some(dir) => Blockchain::open(&allocation, dir.as_ref()).unwrap_or_else(|e| {
+            error!("Error opening data directory {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => Blockchain::with_allocation(&allocation),
+    };
     let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool_size = matches
+        .value_of("mempool_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool size: {}", e);
+            process::exit(1);
+        });
+    let mempool = Arc::new(Mutex::new(Mempool::new(mempool_size)));
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -64,6 +105,28 @@ fn main() {
     let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
     server_ctx.start().unwrap();
 
+    // start the miner
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
+    let coinbase_address = matches.value_of("coinbase_address").map(|address| {
+        address.parse::<Address>().unwrap_or_else(|e| {
+            error!("Error parsing coinbase address {}: {}", address, e);
+            process::exit(1);
+        })
+    });
+    let (miner_ctx, miner, finished_block_chan) =
+        miner::new(&blockchain, &mempool, miner_threads, coinbase_address);
+    let miner_worker_ctx =
+        miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &mempool, &miner);
+    miner_ctx.start();
+    miner_worker_ctx.start();
+
     // start the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
@@ -73,14 +136,18 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
-    let worker_ctx = network::worker::Worker::new(p2p_workers, msg_rx, &server);
+    let worker_ctx =
+        network::worker::Worker::new(p2p_workers, msg_rx, &server, &blockchain, &mempool, &miner);
     worker_ctx.start();
 
-    // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new();
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan);
-    miner_ctx.start();
-    miner_worker_ctx.start();
+    // start the transaction generator, sending between the funded accounts this node holds
+    #[cfg(not(feature = "utxo"))]
+    let generator = {
+        let (generator_ctx, generator) =
+            generator::new(&server, &blockchain, &mempool, &miner, allocation.keys());
+        generator_ctx.start();
+        generator
+    };
 
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
@@ -116,7 +183,15 @@ fn main() {
     }
 
     // start the API server
-    ApiServer::start(api_addr, &miner, &server, &blockchain);
+    ApiServer::start(
+        api_addr,
+        &miner,
+        #[cfg(not(feature = "utxo"))]
+        &generator,
+        &server,
+        &blockchain,
+        &mempool,
+    );
 
     loop {
         std::thread::park();
@@ -0,0 +1,589 @@
+use crate::blockchain::Blockchain;
+use crate::state::State;
+#[cfg(not(feature = "utxo"))]
+use crate::types::address::Address;
+use crate::types::hash::{Hashable, H256};
+#[cfg(feature = "utxo")]
+use crate::types::transaction::OutPoint;
+use crate::types::transaction::{BlockTransaction, SignedTransaction};
+use log::debug;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+/// How long a transaction may wait in the mempool before it is evicted
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A fee per serialized byte, compared exactly as a fraction.
+#[derive(Debug, Clone, Copy)]
+pub struct FeeRate {
+    fee: u64,
+    size: u64,
+}
+
+impl FeeRate {
+    pub fn new(fee: u64, size: u64) -> Self {
+        Self { fee, size }
+    }
+
+    pub fn of(tx: &SignedTransaction) -> Self {
+        Self::new(tx.fee(), tx.size())
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.fee as f64 / self.size as f64
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &FeeRate) -> Ordering {
+        let left = u128::from(self.fee) * u128::from(other.size);
+        let right = u128::from(other.fee) * u128::from(self.size);
+        left.cmp(&right)
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &FeeRate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &FeeRate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+/// The transactions of a queue are included in order: those of a sender in the account model.
+#[cfg(not(feature = "utxo"))]
+type Queue = Address;
+/// The transactions of a queue are included in order: a single transaction in the UTXO model.
+#[cfg(feature = "utxo")]
+type Queue = H256;
+
+/// The queue a pending transaction waits in, and its position in the queue. The transactions of a
+/// sender are included in account nonce order.
+#[cfg(not(feature = "utxo"))]
+fn queue_position(_hash: &H256, tx: &SignedTransaction) -> (Queue, u32) {
+    (tx.transaction.sender, tx.transaction.account_nonce)
+}
+
+/// The queue a pending transaction waits in, and its position in the queue. Every transaction has
+/// a queue of its own, since it only spends outputs that are in the chain already.
+#[cfg(feature = "utxo")]
+fn queue_position(hash: &H256, _tx: &SignedTransaction) -> (Queue, u32) {
+    (*hash, 0)
+}
+
+/// What a transaction uses up when it applies, so that two pending transactions using the same
+/// one conflict: a nonce of its sender in the account model.
+#[cfg(not(feature = "utxo"))]
+type Spend = (Address, u32);
+/// What a transaction uses up when it applies, so that two pending transactions using the same
+/// one conflict: an output in the UTXO model.
+#[cfg(feature = "utxo")]
+type Spend = OutPoint;
+
+#[cfg(not(feature = "utxo"))]
+fn spends(tx: &SignedTransaction) -> Vec<Spend> {
+    vec![(tx.transaction.sender, tx.transaction.account_nonce)]
+}
+
+#[cfg(feature = "utxo")]
+fn spends(tx: &SignedTransaction) -> Vec<Spend> {
+    tx.transaction.inputs.clone()
+}
+
+struct Entry {
+    tx: SignedTransaction,
+    rate: FeeRate,
+    /// Arrival sequence number, which breaks ties between equal fee rates
+    seq: u64,
+    arrival: Instant,
+}
+
+/// Pending signed transactions that are not in the longest chain yet.
+pub struct Mempool {
+    /// Every pending transaction keyed by its hash
+    transactions: HashMap<H256, Entry>,
+    /// The hash of the pending transaction using up each spend
+    spent_by: HashMap<Spend, H256>,
+    next_seq: u64,
+    max_size: usize,
+}
+
+impl Mempool {
+    /// Create a mempool holding at most `max_size` transactions.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            transactions: HashMap::new(),
+            spent_by: HashMap::new(),
+            next_seq: 0,
+            max_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.transactions.get(hash).map(|entry| &entry.tx)
+    }
+
+    /// The highest account nonce among the pending transactions of `sender`, if it has any.
+    #[cfg(not(feature = "utxo"))]
+    pub fn pending_nonce(&self, sender: &Address) -> Option<u32> {
+        self.transactions
+            .values()
+            .filter(|entry| entry.tx.transaction.sender == *sender)
+            .map(|entry| entry.tx.transaction.account_nonce)
+            .max()
+    }
+
+    /// Add a transaction. Returns whether it was added, i.e., it is not a duplicate, it verifies,
+    /// it may apply on top of `state`, the state at the tip, it pays a higher fee rate than every
+    /// pending transaction it conflicts with, which it replaces, and the mempool is not full, or it
+    /// pays a higher fee rate than a transaction that can be evicted to make room.
+    pub fn insert(&mut self, tx: SignedTransaction, state: &State) -> bool {
+        let hash = tx.hash();
+        if self.transactions.contains_key(&hash) {
+            return false;
+        }
+        if !tx.verify() {
+            debug!("Mempool rejected unverified transaction {:?}", hash);
+            return false;
+        }
+        if let Err(e) = state.check_pending(&tx) {
+            debug!("Mempool rejected transaction {:?}: {}", hash, e);
+            return false;
+        }
+        let rate = FeeRate::of(&tx);
+        let mut conflicts: Vec<H256> = spends(&tx)
+            .iter()
+            .filter_map(|spend| self.spent_by.get(spend).copied())
+            .collect();
+        conflicts.sort();
+        conflicts.dedup();
+        if let Some(conflict) = conflicts
+            .iter()
+            .find(|conflict| self.transactions[*conflict].rate >= rate)
+        {
+            debug!(
+                "Mempool rejected transaction {:?} conflicting with {:?}",
+                hash, conflict
+            );
+            return false;
+        }
+        for conflict in conflicts.iter() {
+            debug!("Mempool replaced transaction {:?} by {:?}", conflict, hash);
+            self.remove(conflict);
+        }
+        if self.transactions.len() >= self.max_size {
+            let (queue, position) = queue_position(&hash, &tx);
+            match self.eviction_candidate(&queue, position) {
+                Some((evicted, evicted_rate)) if rate > evicted_rate => {
+                    debug!("Mempool full, evicted transaction {:?}", evicted);
+                    self.remove(&evicted);
+                }
+                _ => {
+                    debug!("Mempool full, dropped transaction {:?}", hash);
+                    return false;
+                }
+            }
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let entry = Entry {
+            tx,
+            rate,
+            seq,
+            arrival: Instant::now(),
+        };
+        for spend in spends(&entry.tx) {
+            self.spent_by.insert(spend, hash);
+        }
+        self.transactions.insert(hash, entry);
+        true
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let entry = self.transactions.remove(hash)?;
+        for spend in spends(&entry.tx).iter() {
+            self.spent_by.remove(spend);
+        }
+        Some(entry.tx)
+    }
+
+    /// Find the transaction to evict to make room for a new one at `position` in `queue`, and its
+    /// fee rate: the lowest rate, and the newest among equal rates, among the last transactions of
+    /// the queues, so that no remaining transaction waits for an evicted one. The transactions
+    /// before the new one in its own queue are kept, since it waits for them.
+    fn eviction_candidate(&self, queue: &Queue, position: u32) -> Option<(H256, FeeRate)> {
+        let mut tails: HashMap<Queue, (u32, &H256, &Entry)> = HashMap::new();
+        for (hash, entry) in self.transactions.iter() {
+            let (q, p) = queue_position(hash, &entry.tx);
+            let tail = tails.entry(q).or_insert((p, hash, entry));
+            if (p, entry.seq) > (tail.0, tail.2.seq) {
+                *tail = (p, hash, entry);
+            }
+        }
+        tails
+            .into_iter()
+            .filter(|(q, (p, _, _))| q != queue || *p >= position)
+            .min_by_key(|(_, (_, _, entry))| (entry.rate, Reverse(entry.seq)))
+            .map(|(_, (_, hash, entry))| (*hash, entry.rate))
+    }
+
+    /// Get up to `n` transactions in the order to fill a block template: the highest fee rate
+    /// first, and the oldest first among equal rates, but the transactions of a sender in account
+    /// nonce order. They stay in the mempool until a block including them joins the longest chain.
+    pub fn take(&self, n: usize) -> Vec<SignedTransaction> {
+        self.ordered()
+            .take(n)
+            .map(|entry| entry.tx.clone())
+            .collect()
+    }
+
+    /// Estimate the fee rate a new transaction must beat to be included within `blocks` blocks of
+    /// `block_size` bytes, if the blocks are filled in the order of `take` and nothing else comes
+    /// in: the highest rate among the pending transactions that do not fit, so that the new one is
+    /// taken before all of them, or zero if all of them fit. It is not the rate of the first one
+    /// that does not fit, since a sender's later transactions may pay more than its earlier ones.
+    pub fn estimate_fee_rate(&self, blocks: u64, block_size: u64) -> FeeRate {
+        let mut space = blocks.saturating_mul(block_size);
+        self.ordered()
+            .skip_while(|entry| match space.checked_sub(entry.rate.size) {
+                Some(left) => {
+                    space = left;
+                    true
+                }
+                None => false,
+            })
+            .map(|entry| entry.rate)
+            .max()
+            .unwrap_or_else(|| FeeRate::new(0, 1))
+    }
+
+    /// Iterate over the pending transactions in the order of `take`, by merging the queues on the
+    /// fee rate of their first transaction.
+    fn ordered(&self) -> impl Iterator<Item = &Entry> {
+        let mut queues = HashMap::new();
+        for (hash, entry) in self.transactions.iter() {
+            let (queue, position) = queue_position(hash, &entry.tx);
+            queues
+                .entry(queue)
+                .or_insert_with(Vec::new)
+                .push((position, entry));
+        }
+        // the first transaction of a queue last, so that it is popped first
+        for txs in queues.values_mut() {
+            txs.sort_by_key(|(position, entry)| Reverse((*position, entry.seq)));
+        }
+        let mut heads: BinaryHeap<_> = queues
+            .iter()
+            .map(|(queue, txs)| {
+                let (_, entry) = txs.last().unwrap();
+                (entry.rate, Reverse(entry.seq), *queue)
+            })
+            .collect();
+        std::iter::from_fn(move || {
+            let (_, _, queue) = heads.pop()?;
+            let txs = queues.get_mut(&queue).unwrap();
+            let (_, entry) = txs.pop().unwrap();
+            if let Some((_, next)) = txs.last() {
+                heads.push((next.rate, Reverse(next.seq), queue));
+            }
+            Some(entry)
+        })
+    }
+
+    /// Follow the tip of the blockchain moving from `old_tip` to its current tip: transactions of
+    /// blocks dropped from the longest chain come back, except coinbases, and transactions of
+    /// blocks joining it are removed, together with those that can no longer be mined.
+    pub fn update(&mut self, blockchain: &Blockchain, old_tip: &H256) {
+        let (retracted, enacted) = blockchain.reorg_path(old_tip, &blockchain.tip());
+        let state = blockchain.state_at(&blockchain.tip()).unwrap();
+        for hash in retracted.iter() {
+            let block = blockchain.get_block(hash).unwrap();
+            for tx in block.content.data.iter() {
+                if let BlockTransaction::Signed(tx) = tx {
+                    self.insert(tx.clone(), state);
+                }
+            }
+        }
+        for hash in enacted.iter() {
+            let block = blockchain.get_block(hash).unwrap();
+            for tx in block.content.data.iter() {
+                self.remove(&tx.hash());
+            }
+        }
+        self.evict(state, Instant::now());
+    }
+
+    /// Remove the transactions that waited longer than `MAX_AGE` by `now`, or that may no longer
+    /// apply on top of `state`, e.g., because their account nonce was used or the sender spent its
+    /// balance.
+    fn evict(&mut self, state: &State, now: Instant) {
+        let evicted: Vec<H256> = self
+            .transactions
+            .iter()
+            .filter(|(hash, entry)| {
+                if now.duration_since(entry.arrival) > MAX_AGE {
+                    debug!("Mempool evicted expired transaction {:?}", hash);
+                    return true;
+                }
+                match state.check_pending(&entry.tx) {
+                    Ok(()) => false,
+                    Err(e) => {
+                        debug!("Mempool evicted transaction {:?}: {}", hash, e);
+                        true
+                    }
+                }
+            })
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in evicted.iter() {
+            self.remove(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "utxo"))]
+    use crate::blockchain::genesis::Allocation;
+    use crate::types::key_pair;
+    use crate::types::transaction::{generate_random_transaction, sign_transaction, Transaction};
+    use ring::signature::KeyPair;
+
+    /// A transfer from a fresh key pair, which gets just enough coins in `state` to pay for it.
+    fn funded_transfer(state: &mut State, fee: u32) -> SignedTransaction {
+        funded_transfers(state, &[fee]).pop().unwrap()
+    }
+
+    /// Conflicting transfers from a fresh key pair, one per fee, which gets just enough coins in
+    /// `state` to pay for any one of them.
+    #[cfg(not(feature = "utxo"))]
+    fn funded_transfers(state: &mut State, fees: &[u32]) -> Vec<SignedTransaction> {
+        let key = key_pair::random();
+        let sender = Address::from_public_key(*key.public_key());
+        let max_fee = fees.iter().max().unwrap();
+        state.credit(sender, 1 + u64::from(*max_fee));
+        fees.iter()
+            .map(|fee| {
+                let transaction = Transaction {
+                    sender,
+                    receiver: crate::types::address::generate_random_address(),
+                    value: 1,
+                    fee: *fee,
+                    account_nonce: 1,
+                };
+                sign_transaction(transaction, &key)
+            })
+            .collect()
+    }
+
+    /// Conflicting transfers from a fresh key pair, one per fee, which gets just enough coins in
+    /// `state` to pay for any one of them.
+    #[cfg(feature = "utxo")]
+    fn funded_transfers(state: &mut State, fees: &[u32]) -> Vec<SignedTransaction> {
+        use crate::types::address::{generate_random_address, Address};
+        use crate::types::transaction::{Coinbase, OutPoint, Output};
+        let key = key_pair::random();
+        let max_fee = u64::from(*fees.iter().max().unwrap());
+        let coinbase = Coinbase {
+            height: 0,
+            receiver: Address::from_public_key(*key.public_key()),
+            value: 1 + max_fee,
+        };
+        state.apply_coinbase(&coinbase).unwrap();
+        fees.iter()
+            .map(|fee| {
+                let fee = u64::from(*fee);
+                let transaction = Transaction {
+                    inputs: vec![OutPoint {
+                        hash: coinbase.hash(),
+                        index: 0,
+                    }],
+                    outputs: vec![Output {
+                        address: generate_random_address(),
+                        value: 1 + max_fee - fee,
+                    }],
+                    fee,
+                };
+                sign_transaction(transaction, &key)
+            })
+            .collect()
+    }
+
+    /// A transfer of 1 coin from the account of the key pair number `sender`, e.g., funded by
+    /// `Allocation::with_indexed_keys`.
+    #[cfg(not(feature = "utxo"))]
+    fn indexed_transfer(sender: u64, fee: u32, nonce: u32) -> SignedTransaction {
+        let key = key_pair::from_index(sender);
+        let transaction = Transaction {
+            sender: Address::from_public_key(*key.public_key()),
+            receiver: Address::from_public_key(*key_pair::from_index(0).public_key()),
+            value: 1,
+            fee,
+            account_nonce: nonce,
+        };
+        sign_transaction(transaction, &key)
+    }
+
+    #[test]
+    fn insert_deduplicate_and_limit() {
+        let mut mempool = Mempool::new(2);
+        let mut state = State::new();
+        let txs: Vec<SignedTransaction> = (0..3).map(|_| funded_transfer(&mut state, 0)).collect();
+        assert!(mempool.insert(txs[0].clone(), &state));
+        assert!(!mempool.insert(txs[0].clone(), &state));
+        let mut forged = txs[1].clone();
+        forged.transaction = generate_random_transaction();
+        assert!(!mempool.insert(forged, &state));
+        let unfunded = funded_transfer(&mut State::new(), 0);
+        assert!(!mempool.insert(unfunded, &state));
+        assert!(mempool.insert(txs[1].clone(), &state));
+        assert!(!mempool.insert(txs[2].clone(), &state));
+        let taken: Vec<H256> = mempool.take(5).iter().map(|tx| tx.hash()).collect();
+        assert_eq!(taken, vec![txs[0].hash(), txs[1].hash()]);
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn replace_conflicting_transaction() {
+        let mut mempool = Mempool::new(10);
+        let mut state = State::new();
+        let txs = funded_transfers(&mut state, &[2, 1, 2, 3]);
+        assert!(mempool.insert(txs[0].clone(), &state));
+        // only a strictly higher fee rate replaces a conflicting transaction
+        assert!(!mempool.insert(txs[1].clone(), &state));
+        assert!(!mempool.insert(txs[2].clone(), &state));
+        assert!(mempool.insert(txs[3].clone(), &state));
+        assert!(!mempool.contains(&txs[0].hash()));
+        assert_eq!(mempool.len(), 1);
+        // once the replacement is gone, nothing conflicts anymore
+        mempool.remove(&txs[3].hash());
+        assert!(mempool.insert(txs[1].clone(), &state));
+    }
+
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    fn update_on_reorg() {
+        use crate::types::block::{generate_block_with, generate_random_block};
+        use crate::types::transaction::generate_random_signed_transaction;
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut mempool = Mempool::new(10);
+        let tx_a = generate_random_signed_transaction();
+        let tx_b = generate_random_signed_transaction();
+        let state = blockchain.state_at(&genesis_hash).unwrap();
+        assert!(mempool.insert(tx_a.clone(), state));
+        assert!(mempool.insert(tx_b.clone(), state));
+
+        let a1 = generate_block_with(&genesis_hash, vec![tx_a.clone()]);
+        let b1 = generate_block_with(&genesis_hash, vec![tx_b.clone()]);
+        let b2 = generate_random_block(&b1.hash());
+        blockchain.insert(&a1).unwrap();
+        mempool.update(&blockchain, &genesis_hash);
+        assert!(!mempool.contains(&tx_a.hash()));
+        assert!(mempool.contains(&tx_b.hash()));
+
+        blockchain.insert(&b1).unwrap();
+        blockchain.insert(&b2).unwrap();
+        mempool.update(&blockchain, &a1.hash());
+        assert!(mempool.contains(&tx_a.hash()));
+        assert!(!mempool.contains(&tx_b.hash()));
+    }
+
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    fn order_by_fee_rate() {
+        let state = Allocation::with_indexed_keys(3, 100).state();
+        // the second transaction of the first sender pays the most, but waits for the first
+        let txs = [
+            indexed_transfer(0, 9, 2),
+            indexed_transfer(0, 1, 1),
+            indexed_transfer(1, 5, 1),
+            indexed_transfer(2, 3, 1),
+        ];
+        let mut mempool = Mempool::new(10);
+        for tx in txs.iter() {
+            assert!(mempool.insert(tx.clone(), &state));
+        }
+        let taken: Vec<H256> = mempool.take(10).iter().map(|tx| tx.hash()).collect();
+        let expected: Vec<H256> = [2, 3, 1, 0].iter().map(|i| txs[*i].hash()).collect();
+        assert_eq!(taken, expected);
+
+        let size = txs[0].size();
+        // the last transaction pays the most, so a new one must beat it until everything fits
+        assert_eq!(
+            mempool.estimate_fee_rate(1, 3 * size),
+            FeeRate::new(9, size)
+        );
+        assert_eq!(mempool.estimate_fee_rate(2, size), FeeRate::new(9, size));
+        assert_eq!(
+            mempool.estimate_fee_rate(1, 4 * size - 1),
+            FeeRate::new(9, size)
+        );
+        assert_eq!(mempool.estimate_fee_rate(1, 4 * size), FeeRate::new(0, 1));
+    }
+
+    #[test]
+    fn evict_expired_and_unminable() {
+        let mut mempool = Mempool::new(10);
+        let mut state = State::new();
+        let txs: Vec<SignedTransaction> = (0..2).map(|_| funded_transfer(&mut state, 0)).collect();
+        let start = Instant::now();
+        for tx in txs.iter() {
+            assert!(mempool.insert(tx.clone(), &state));
+        }
+        let mut next = state.clone();
+        next.apply_transaction(&txs[0]).unwrap();
+        mempool.evict(&next, Instant::now());
+        assert!(!mempool.contains(&txs[0].hash()));
+        assert!(mempool.contains(&txs[1].hash()));
+        mempool.evict(&next, start + MAX_AGE);
+        assert!(mempool.contains(&txs[1].hash()));
+        mempool.evict(&next, Instant::now() + MAX_AGE + Duration::from_secs(1));
+        assert!(mempool.is_empty());
+    }
+
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    fn evict_lowest_fee_rate_when_full() {
+        let state = Allocation::with_indexed_keys(4, 100).state();
+        let mut mempool = Mempool::new(3);
+        let first = indexed_transfer(0, 1, 1);
+        let second = indexed_transfer(0, 9, 2);
+        let cheap = indexed_transfer(1, 2, 1);
+        for tx in [&first, &second, &cheap].iter() {
+            assert!(mempool.insert((*tx).clone(), &state));
+        }
+        // the first transaction of the first sender pays the least, but the second waits for it
+        let better = indexed_transfer(2, 5, 1);
+        assert!(mempool.insert(better.clone(), &state));
+        assert!(!mempool.contains(&cheap.hash()));
+        assert!(mempool.contains(&first.hash()));
+        assert!(!mempool.insert(indexed_transfer(3, 5, 1), &state));
+        // a transaction does not evict the one before it from the same sender
+        assert!(!mempool.insert(indexed_transfer(2, 6, 2), &state));
+        let third = indexed_transfer(0, 6, 3);
+        assert!(mempool.insert(third.clone(), &state));
+        assert!(!mempool.contains(&better.hash()));
+        assert_eq!(mempool.len(), 3);
+    }
+}
@@ -1,17 +1,29 @@
 pub mod worker;
 
-use log::info;
+use log::{debug, info};
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{select, unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::types::block::Block;
+use crate::blockchain::reward::RewardSchedule;
+use crate::blockchain::validation::MAX_BLOCK_SIZE;
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::state::State;
+use crate::types::address::Address;
+use crate::types::block::{current_timestamp, Block, Content};
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{BlockTransaction, Coinbase};
 
 enum ControlSignal {
-    Start(u64), // the number controls the lambda of interval between block generation
-    Update,     // update the block in mining, it may due to new blockchain tip or new transaction
+    /// The first number controls the lambda of interval between hashes, the second optionally
+    /// changes the number of hashing threads
+    Start(u64, Option<usize>),
+    Update, // update the block in mining, it may due to new blockchain tip or new transaction
     Exit,
 }
 
@@ -21,11 +33,36 @@ enum OperatingState {
     ShutDown,
 }
 
+/// A block template handed to a hashing thread.
+struct Job {
+    template: Block,
+    /// The generation this job belongs to. The thread abandons the job once the generation moves on.
+    generation: u64,
+    lambda: u64,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     finished_block_chan: Sender<Block>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    num_threads: usize,
+    /// Channels for sending jobs to the hashing threads, one per thread
+    hashers: Vec<Sender<Job>>,
+    /// Generation of the current template, bumped whenever the template is replaced
+    generation: Arc<AtomicU64>,
+    solution_sender: Sender<(u64, Block)>,
+    /// Channel for receiving solved blocks from the hashing threads, tagged with their generation
+    solution_chan: Receiver<(u64, Block)>,
+    /// The ledger state after the transactions of the current template
+    template_state: State,
+    /// Height of the current template
+    template_height: u64,
+    /// Where the block rewards go, or nowhere if the blocks carry no coinbase
+    coinbase_address: Option<Address>,
+    reward: RewardSchedule,
 }
 
 #[derive(Clone)]
@@ -34,14 +71,31 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new() -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    num_threads: usize,
+    coinbase_address: Option<Address>,
+) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let (solution_sender, solution_receiver) = unbounded();
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         finished_block_chan: finished_block_sender,
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        num_threads: num_threads.max(1),
+        hashers: vec![],
+        generation: Arc::new(AtomicU64::new(0)),
+        solution_sender,
+        solution_chan: solution_receiver,
+        template_state: State::new(),
+        template_height: 0,
+        coinbase_address,
+        reward: blockchain.lock().unwrap().reward(),
     };
 
     let handle = Handle {
@@ -51,9 +105,15 @@ pub fn new() -> (Context, Handle, Receiver<Block>) {
     (ctx, handle, finished_block_receiver)
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
+#[cfg_attr(not(test), allow(dead_code))]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
-    new()
+    new(
+        &Arc::new(Mutex::new(Blockchain::new())),
+        &Arc::new(Mutex::new(Mempool::new(0))),
+        1,
+        None,
+    )
 }
 
 impl Handle {
@@ -63,7 +123,14 @@ impl Handle {
 
     pub fn start(&self, lambda: u64) {
         self.control_chan
-            .send(ControlSignal::Start(lambda))
+            .send(ControlSignal::Start(lambda, None))
+            .unwrap();
+    }
+
+    /// Start the miner with a new number of hashing threads.
+    pub fn start_with_threads(&self, lambda: u64, num_threads: usize) {
+        self.control_chan
+            .send(ControlSignal::Start(lambda, Some(num_threads)))
             .unwrap();
     }
 
@@ -83,63 +150,254 @@ impl Context {
         info!("Miner initialized into paused mode");
     }
 
+    /// Build a block template on top of the current tip of the blockchain.
+    fn template_on_tip(&mut self) -> Block {
+        let (template, state, height) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let tip = blockchain.tip();
+            let height = blockchain.get_height(&tip).unwrap() + 1;
+            let difficulty = blockchain.expected_difficulty(&tip).unwrap();
+            let state = blockchain.state_at(&tip).unwrap();
+            let (template, state) = self.template(
+                blockchain.get_block(&tip).unwrap(),
+                height,
+                difficulty,
+                state,
+            );
+            (template, state, height)
+        };
+        self.template_state = state;
+        self.template_height = height;
+        template
+    }
+
+    /// Build a block template with nonce 0 at `height` on top of `parent`, filled with the
+    /// transactions from the mempool that apply to `state`, the state after the parent, and a
+    /// coinbase claiming the subsidy and the fees if there is a coinbase address. Returns the
+    /// template and the state after it. The timestamp never goes back in time, even if the
+    /// parent's is ahead of the local clock.
+    fn template(
+        &self,
+        parent: &Block,
+        height: u64,
+        difficulty: H256,
+        state: &State,
+    ) -> (Block, State) {
+        let mut state = state.clone();
+        let mut fees: u64 = 0;
+        let mut space = MAX_BLOCK_SIZE;
+        // greedily by fee rate: the mempool may still hold transactions of the parent, or
+        // transactions whose account nonce comes after one that is not in the mempool yet or
+        // did not fit
+        let mempool = self.mempool.lock().unwrap();
+        let mut data: Vec<BlockTransaction> = mempool
+            .take(mempool.len())
+            .into_iter()
+            .filter(|tx| {
+                let size = tx.size();
+                if size > space {
+                    return false;
+                }
+                match state.apply_transaction(tx) {
+                    Ok(fee) => {
+                        fees = fees.saturating_add(fee);
+                        space -= size;
+                        true
+                    }
+                    Err(_) => false,
+                }
+            })
+            .map(BlockTransaction::from)
+            .collect();
+        drop(mempool);
+        if let Some(receiver) = self.coinbase_address {
+            let coinbase = Coinbase {
+                height,
+                receiver,
+                value: self.reward.subsidy(height).saturating_add(fees),
+            };
+            if state.apply_coinbase(&coinbase).is_ok() {
+                data.insert(0, BlockTransaction::Coinbase(coinbase));
+            }
+        }
+        let timestamp = current_timestamp().max(parent.get_timestamp());
+        let block = Block::new(parent.hash(), difficulty, timestamp, Content { data });
+        (block, state)
+    }
+
+    /// Invalidate the jobs of all hashing threads.
+    fn abandon_jobs(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Hand a new template to every hashing thread, spawning the threads if needed. Thread `i`
+    /// tries the nonces `i`, `i + n`, `i + 2n`, ... where `n` is the number of threads.
+    fn dispatch(&mut self, template: &Block, lambda: u64) {
+        if self.hashers.len() != self.num_threads {
+            self.abandon_jobs();
+            // dropping the old senders makes the old threads exit
+            self.hashers = (0..self.num_threads)
+                .map(|i| {
+                    let (sender, receiver) = unbounded();
+                    let generation = Arc::clone(&self.generation);
+                    let solution_chan = self.solution_sender.clone();
+                    let num_threads = self.num_threads as u32;
+                    thread::Builder::new()
+                        .name(format!("miner-hasher-{}", i))
+                        .spawn(move || {
+                            hasher_loop(i as u32, num_threads, receiver, generation, solution_chan);
+                        })
+                        .unwrap();
+                    sender
+                })
+                .collect();
+            debug!("Miner spawned {} hashing threads", self.num_threads);
+        }
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        for hasher in self.hashers.iter() {
+            hasher
+                .send(Job {
+                    template: template.clone(),
+                    generation,
+                    lambda,
+                })
+                .unwrap();
+        }
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Miner shutting down");
+                self.operating_state = OperatingState::ShutDown;
+                self.abandon_jobs();
+                self.hashers.clear();
+            }
+            ControlSignal::Start(i, num_threads) => {
+                if let Some(n) = num_threads {
+                    self.num_threads = n.max(1);
+                }
+                info!(
+                    "Miner starting in continuous mode with lambda {} and {} threads",
+                    i, self.num_threads
+                );
+                self.operating_state = OperatingState::Run(i);
+                let template = self.template_on_tip();
+                self.dispatch(&template, i);
+            }
+            ControlSignal::Update => {
+                if let OperatingState::Run(i) = self.operating_state {
+                    let template = self.template_on_tip();
+                    self.dispatch(&template, i);
+                }
+                // in paused state, don't need to update
+            }
+        }
+    }
+
     fn miner_loop(&mut self) {
-        // main mining loop
+        // main mining loop, the hashing itself happens in the hashing threads
         loop {
-            // check and react to control signals
+            // check and react to control signals and solved blocks
             match self.operating_state {
                 OperatingState::Paused => {
                     let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
-                    continue;
+                    self.handle_signal(signal);
                 }
                 OperatingState::ShutDown => {
                     return;
                 }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                unimplemented!()
-                            }
-                        };
+                OperatingState::Run(i) => select! {
+                    recv(self.control_chan) -> signal => {
+                        let signal = signal.expect("Miner control channel detached");
+                        self.handle_signal(signal);
+                    }
+                    recv(self.solution_chan) -> solution => {
+                        let (generation, block) = solution.unwrap();
+                        if generation != self.generation.load(Ordering::SeqCst) {
+                            // solved a template that was already replaced
+                            continue;
+                        }
+                        self.abandon_jobs();
+                        self.finished_block_chan
+                            .send(block.clone())
+                            .expect("Send finished block error");
+                        // keep mining on top of our own block, the miner worker will insert it.
+                        // If its parent is not inserted yet either, keep the difficulty; a
+                        // wrong guess at a retarget is replaced on the next update.
+                        let difficulty = self
+                            .blockchain
+                            .lock()
+                            .unwrap()
+                            .next_difficulty(&block)
+                            .unwrap_or_else(|| block.get_difficulty());
+                        let height = self.template_height + 1;
+                        let (template, state) =
+                            self.template(&block, height, difficulty, &self.template_state);
+                        self.template_state = state;
+                        self.template_height = height;
+                        self.dispatch(&template, i);
                     }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            }
-
-            // TODO for student: actual mining, create a block
-            // TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+        }
+    }
+}
 
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+/// Search the nonces of each received job until one of them solves the proof-of-work puzzle or
+/// the job is abandoned. A newer job replaces the current one without restarting the interval
+/// between hashes, so that frequent template updates do not starve the search. Exits when the job
+/// channel is closed.
+fn hasher_loop(
+    first_nonce: u32,
+    stride: u32,
+    job_chan: Receiver<Job>,
+    generation: Arc<AtomicU64>,
+    solution_chan: Sender<(u64, Block)>,
+) {
+    let mut job = match job_chan.recv() {
+        Ok(job) => job,
+        Err(_) => return,
+    };
+    let mut block = job.template.clone();
+    block.header.nonce = first_nonce;
+    let mut solved = false;
+    loop {
+        if job.lambda != 0 {
+            let interval = time::Duration::from_micros(job.lambda);
+            thread::sleep(interval);
+        }
+        // skip to the newest job, or wait for one if the current job is abandoned or solved
+        loop {
+            let next = if !solved && generation.load(Ordering::Relaxed) == job.generation {
+                match job_chan.try_recv() {
+                    Ok(next) => next,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match job_chan.recv() {
+                    Ok(next) => next,
+                    Err(_) => return,
                 }
+            };
+            job = next;
+            block = job.template.clone();
+            block.header.nonce = first_nonce;
+            solved = false;
+        }
+        if block.hash() <= block.get_difficulty() {
+            // the miner thread may have exited already
+            let _ = solution_chan.send((job.generation, block.clone()));
+            solved = true;
+            continue;
+        }
+        match block.header.nonce.checked_add(stride) {
+            Some(nonce) => block.header.nonce = nonce,
+            None => {
+                // nonce space exhausted, a new timestamp gives a fresh one
+                block.header.timestamp = current_timestamp();
+                block.header.nonce = first_nonce;
             }
         }
     }
@@ -165,6 +423,21 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_three_block_multithreaded() {
+        let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        miner_ctx.start();
+        miner_handle.start_with_threads(0, 4);
+        let mut block_prev = finished_block_chan.recv().unwrap();
+        for _ in 0..2 {
+            let block_next = finished_block_chan.recv().unwrap();
+            assert_eq!(block_prev.hash(), block_next.get_parent());
+            block_prev = block_next;
+        }
+        miner_handle.exit();
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
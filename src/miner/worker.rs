@@ -1,20 +1,38 @@
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::miner::Handle as MinerHandle;
+use crate::network::message::Message;
 use crate::network::server::Handle as ServerHandle;
 use crate::types::block::Block;
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use crate::types::hash::Hashable;
+use crossbeam::channel::Receiver;
+use log::{debug, info, warn};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
 }
 
 impl Worker {
-    pub fn new(server: &ServerHandle, finished_block_chan: Receiver<Block>) -> Self {
+    pub fn new(
+        server: &ServerHandle,
+        finished_block_chan: Receiver<Block>,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        miner: &MinerHandle,
+    ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            miner: miner.clone(),
         }
     }
 
@@ -30,11 +48,26 @@ impl Worker {
 
     fn worker_loop(&self) {
         loop {
-            let _block = self
+            let block = self
                 .finished_block_chan
                 .recv()
                 .expect("Receive finished block error");
-            // TODO for student: insert this finished block to blockchain, and broadcast this block hash
+            let hash = block.hash();
+            let mut blockchain = self.blockchain.lock().unwrap();
+            let tip = blockchain.tip();
+            match blockchain.insert(&block) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!("Mined block {:?} is invalid: {}", hash, e);
+                    continue;
+                }
+            }
+            self.mempool.lock().unwrap().update(&blockchain, &tip);
+            drop(blockchain);
+            debug!("Mined block {:?}", hash);
+            self.server.broadcast(Message::NewBlockHashes(vec![hash]));
+            self.miner.update();
         }
     }
 }
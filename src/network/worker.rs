@@ -1,21 +1,37 @@
 use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
-use crate::types::hash::H256;
+use crate::blockchain::orphan::OrphanBuffer;
+use crate::blockchain::validation;
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::miner::Handle as MinerHandle;
+use crate::types::hash::{Hashable, H256};
 
 use log::{debug, error, warn};
 
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
-#[cfg(any(test, test_utilities))]
+/// Maximum number of blocks kept in the orphan buffer
+const MAX_ORPHAN_BLOCKS: usize = 1024;
+/// Maximum time a block is kept in the orphan buffer waiting for its parent
+const MAX_ORPHAN_AGE: Duration = Duration::from_secs(600);
+
+#[cfg(any(test, feature = "test-utilities"))]
 use super::peer::TestReceiver as PeerTestReceiver;
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 use super::server::TestReceiver as ServerTestReceiver;
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    orphans: Arc<Mutex<OrphanBuffer>>,
+    mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
 }
 
 impl Worker {
@@ -23,11 +39,21 @@ impl Worker {
         num_worker: usize,
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        miner: &MinerHandle,
     ) -> Self {
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            orphans: Arc::new(Mutex::new(OrphanBuffer::new(
+                MAX_ORPHAN_BLOCKS,
+                MAX_ORPHAN_AGE,
+            ))),
+            mempool: Arc::clone(mempool),
+            miner: miner.clone(),
         }
     }
 
@@ -60,17 +86,163 @@ impl Worker {
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
                 }
-                _ => unimplemented!(),
+                Message::NewBlockHashes(hashes) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let orphans = self.orphans.lock().unwrap();
+                    let unknown: Vec<H256> = hashes
+                        .into_iter()
+                        .filter(|h| !blockchain.contains(h) && !orphans.contains(h))
+                        .collect();
+                    drop(orphans);
+                    drop(blockchain);
+                    if !unknown.is_empty() {
+                        peer.write(Message::GetBlocks(unknown));
+                    }
+                }
+                Message::GetBlocks(hashes) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let blocks: Vec<_> = hashes
+                        .iter()
+                        .filter_map(|h| blockchain.get_block(h).cloned())
+                        .collect();
+                    drop(blockchain);
+                    if !blocks.is_empty() {
+                        peer.write(Message::Blocks(blocks));
+                    }
+                }
+                Message::Blocks(blocks) => {
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    let mut orphans = self.orphans.lock().unwrap();
+                    let tip = blockchain.tip();
+                    let mut new_hashes: Vec<H256> = vec![];
+                    let mut missing_parents: Vec<H256> = vec![];
+                    for block in blocks {
+                        let hash = block.hash();
+                        let parent = block.get_parent();
+                        if blockchain.contains(&hash) || orphans.contains(&hash) {
+                            continue;
+                        }
+                        if let Err(e) = validation::check_block(&block) {
+                            warn!("Rejected block {:?} from {}: {}", hash, peer.addr(), e);
+                            continue;
+                        }
+                        if !blockchain.contains(&parent) {
+                            if let Err(e) = validation::check_orphan(&blockchain, &block) {
+                                warn!(
+                                    "Rejected orphan block {:?} from {}: {}",
+                                    hash,
+                                    peer.addr(),
+                                    e
+                                );
+                                continue;
+                            }
+                            // an orphan parent is already waiting for its own parent
+                            if !orphans.contains(&parent) && !missing_parents.contains(&parent) {
+                                missing_parents.push(parent);
+                            }
+                            orphans.insert(block);
+                            continue;
+                        }
+                        match blockchain.insert_checked(&block) {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(e) => {
+                                warn!("Rejected block {:?} from {}: {}", hash, peer.addr(), e);
+                                continue;
+                            }
+                        }
+                        new_hashes.push(hash);
+                        // connect the orphans waiting for this block, and their descendants, which
+                        // were checked before being buffered
+                        let mut connected = vec![hash];
+                        while let Some(parent) = connected.pop() {
+                            for child in orphans.take_children(&parent) {
+                                match blockchain.insert_checked(&child) {
+                                    Ok(true) => {
+                                        new_hashes.push(child.hash());
+                                        connected.push(child.hash());
+                                    }
+                                    Ok(false) => {}
+                                    Err(e) => {
+                                        warn!("Rejected orphan block {:?}: {}", child.hash(), e)
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    let tip_changed = blockchain.tip() != tip;
+                    if tip_changed {
+                        self.mempool.lock().unwrap().update(&blockchain, &tip);
+                    }
+                    drop(orphans);
+                    drop(blockchain);
+                    if !missing_parents.is_empty() {
+                        peer.write(Message::GetBlocks(missing_parents));
+                    }
+                    if tip_changed {
+                        self.miner.update();
+                    }
+                    if !new_hashes.is_empty() {
+                        self.server.broadcast(Message::NewBlockHashes(new_hashes));
+                    }
+                }
+                Message::NewTransactionHashes(hashes) => {
+                    let mempool = self.mempool.lock().unwrap();
+                    let unknown: Vec<H256> = hashes
+                        .into_iter()
+                        .filter(|h| !mempool.contains(h))
+                        .collect();
+                    drop(mempool);
+                    if !unknown.is_empty() {
+                        peer.write(Message::GetTransactions(unknown));
+                    }
+                }
+                Message::GetTransactions(hashes) => {
+                    let mempool = self.mempool.lock().unwrap();
+                    let txs: Vec<_> = hashes
+                        .iter()
+                        .filter_map(|h| mempool.get(h).cloned())
+                        .collect();
+                    drop(mempool);
+                    if !txs.is_empty() {
+                        peer.write(Message::Transactions(txs));
+                    }
+                }
+                Message::Transactions(txs) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let state = blockchain.state_at(&blockchain.tip()).unwrap();
+                    let mut mempool = self.mempool.lock().unwrap();
+                    let mut new_hashes: Vec<H256> = vec![];
+                    // the mempool checks the signature and the state at the tip, and only the
+                    // transactions it admits are relayed
+                    for tx in txs {
+                        let hash = tx.hash();
+                        if mempool.insert(tx, state) {
+                            new_hashes.push(hash);
+                        } else {
+                            debug!("Did not admit transaction {:?} from {}", hash, peer.addr());
+                        }
+                    }
+                    drop(mempool);
+                    drop(blockchain);
+                    if !new_hashes.is_empty() {
+                        self.miner.update();
+                        self.server
+                            .broadcast(Message::NewTransactionHashes(new_hashes));
+                    }
+                }
             }
         }
     }
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
+#[cfg_attr(not(test), allow(dead_code))]
 struct TestMsgSender {
     s: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
 }
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
+#[cfg_attr(not(test), allow(dead_code))]
 impl TestMsgSender {
     fn new() -> (
         TestMsgSender,
@@ -87,14 +259,25 @@ impl TestMsgSender {
         r
     }
 }
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
+#[cfg_attr(not(test), allow(dead_code))]
+const TEST_MEMPOOL_SIZE: usize = 1000;
+#[cfg(any(test, feature = "test-utilities"))]
+#[cfg_attr(not(test), allow(dead_code))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
     let (server, server_receiver) = ServerHandle::new_for_test();
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
-    let worker = Worker::new(1, msg_chan, &server);
+    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    let mempool = Arc::new(Mutex::new(Mempool::new(TEST_MEMPOOL_SIZE)));
+    let (miner_ctx, miner, _finished_block_chan) =
+        crate::miner::new(&blockchain, &mempool, 1, None);
+    // the miner stays paused, it only consumes the update signals
+    miner_ctx.start();
+    let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, &miner);
     worker.start();
-    (test_msg_sender, server_receiver, vec![])
+    let longest_chain = blockchain.lock().unwrap().all_blocks_in_longest_chain();
+    (test_msg_sender, server_receiver, longest_chain)
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -103,6 +286,7 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
 mod test {
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
+    use crate::types::transaction::generate_random_signed_transaction;
     use ntest::timeout;
 
     use super::super::message::Message;
@@ -149,6 +333,111 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn reply_new_transaction_hashes() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let tx = generate_random_signed_transaction();
+        let mut peer_receiver =
+            test_msg_sender.send(Message::NewTransactionHashes(vec![tx.hash()]));
+        let reply = peer_receiver.recv();
+        if let Message::GetTransactions(v) = reply {
+            assert_eq!(v, vec![tx.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    // in the UTXO model, a random transaction spends an output that does not exist
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    #[timeout(60000)]
+    fn reply_transactions() {
+        use crate::types::address::Address;
+        use crate::types::key_pair;
+        use crate::types::transaction::{generate_random_transaction, sign_transaction};
+        use ring::signature::KeyPair;
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let tx = generate_random_signed_transaction();
+        let mut forged = tx.clone();
+        forged.transaction = generate_random_transaction();
+        let key = key_pair::random();
+        let mut transaction = generate_random_transaction();
+        transaction.sender = Address::from_public_key(*key.public_key());
+        transaction.value = 1;
+        let unfunded = sign_transaction(transaction, &key);
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::Transactions(vec![forged, unfunded, tx.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewTransactionHashes(v) = reply {
+            assert_eq!(v, vec![tx.hash()]);
+        } else {
+            panic!();
+        }
+        let mut peer_receiver = test_msg_sender.send(Message::GetTransactions(vec![tx.hash()]));
+        let reply = peer_receiver.recv();
+        if let Message::Transactions(v) = reply {
+            assert_eq!(1, v.len());
+            assert_eq!(tx.hash(), v[0].hash())
+        } else {
+            panic!();
+        }
+    }
+    // a second transaction with the same account nonce conflicts with the first one
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    #[timeout(60000)]
+    fn reply_conflicting_transactions() {
+        use crate::types::address::{generate_random_address, Address};
+        use crate::types::key_pair;
+        use crate::types::transaction::{generate_random_transaction, sign_transaction};
+        use ring::signature::KeyPair;
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let key = key_pair::random();
+        let mut transaction = generate_random_transaction();
+        transaction.sender = Address::from_public_key(*key.public_key());
+        let tx = sign_transaction(transaction.clone(), &key);
+        transaction.receiver = generate_random_address();
+        let conflicting = sign_transaction(transaction, &key);
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::Transactions(vec![tx.clone(), conflicting.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewTransactionHashes(v) = reply {
+            assert_eq!(v, vec![tx.hash()]);
+        } else {
+            panic!();
+        }
+        // the next relayed transaction is a later one, so the conflicting one was not relayed
+        let later = generate_random_signed_transaction();
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![conflicting]));
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![later.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewTransactionHashes(v) = reply {
+            assert_eq!(v, vec![later.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_orphan_blocks() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let parent = generate_random_block(v.last().unwrap());
+        let child = generate_random_block(&parent.hash());
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![child.clone()]));
+        let reply = peer_receiver.recv();
+        if let Message::GetBlocks(v) = reply {
+            assert_eq!(v, vec![parent.hash()]);
+        } else {
+            panic!();
+        }
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![parent.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewBlockHashes(v) = reply {
+            assert_eq!(v, vec![parent.hash(), child.hash()]);
+        } else {
+            panic!();
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,277 @@
+use super::TransactionError;
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::transaction::{BlockTransaction, Coinbase, SignedTransaction};
+use std::collections::HashMap;
+
+/// How far ahead of its sender's account nonce a pending transaction may be
+const MAX_NONCE_GAP: u32 = 64;
+
+/// The ledger state: the account nonce and balance of every address that has been funded.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct State {
+    accounts: HashMap<Address, (u32, u64)>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the account nonce and balance of an address. Unknown addresses have both at 0.
+    pub fn get(&self, address: &Address) -> (u32, u64) {
+        self.accounts.get(address).cloned().unwrap_or((0, 0))
+    }
+
+    /// Add coins to an address out of thin air, e.g., for the initial coin offering.
+    pub fn credit(&mut self, address: Address, amount: u64) {
+        let account = self.accounts.entry(address).or_insert((0, 0));
+        account.1 = account.1.saturating_add(amount);
+    }
+
+    /// Get every account as (address, account nonce, balance), in no particular order.
+    pub fn accounts(&self) -> Vec<(Address, u32, u64)> {
+        self.accounts
+            .iter()
+            .map(|(address, (nonce, balance))| (*address, *nonce, *balance))
+            .collect()
+    }
+
+    /// Check that a transaction applies on top of this state, i.e., the sender can afford the
+    /// value and the fee, and return the fee.
+    pub fn check_transaction(&self, tx: &SignedTransaction) -> Result<u64, TransactionError> {
+        let fee = tx.fee();
+        let tx = &tx.transaction;
+        let (nonce, balance) = self.get(&tx.sender);
+        let expected = nonce.wrapping_add(1);
+        if tx.account_nonce != expected {
+            return Err(TransactionError::WrongNonce {
+                expected,
+                actual: tx.account_nonce,
+            });
+        }
+        let value = u64::from(tx.value);
+        if balance < value + fee {
+            return Err(TransactionError::InsufficientBalance {
+                balance,
+                value: value + fee,
+            });
+        }
+        if tx.receiver != tx.sender {
+            let (_, receiver_balance) = self.get(&tx.receiver);
+            if receiver_balance.checked_add(value).is_none() {
+                return Err(TransactionError::BalanceOverflow);
+            }
+        }
+        Ok(fee)
+    }
+
+    /// Apply a transaction: move the value from the sender to the receiver, take the fee from the
+    /// sender and bump the sender's account nonce. Returns the fee, and leaves the state unchanged
+    /// on error.
+    pub fn apply_transaction(&mut self, tx: &SignedTransaction) -> Result<u64, TransactionError> {
+        let fee = self.check_transaction(tx)?;
+        let tx = &tx.transaction;
+        let value = u64::from(tx.value);
+        let sender = self.accounts.entry(tx.sender).or_insert((0, 0));
+        sender.0 = tx.account_nonce;
+        sender.1 -= value + fee;
+        let receiver = self.accounts.entry(tx.receiver).or_insert((0, 0));
+        receiver.1 += value;
+        Ok(fee)
+    }
+
+    /// Credit the value of a coinbase to its receiver. The state is unchanged on error.
+    pub fn apply_coinbase(&mut self, coinbase: &Coinbase) -> Result<(), TransactionError> {
+        let (_, balance) = self.get(&coinbase.receiver);
+        if balance.checked_add(coinbase.value).is_none() {
+            return Err(TransactionError::BalanceOverflow);
+        }
+        self.credit(coinbase.receiver, coinbase.value);
+        Ok(())
+    }
+
+    /// Apply the transactions of the block at `height` in order, then its coinbase, which may pay
+    /// up to `subsidy` plus the fees. Returns the resulting state, or the index of the first failing
+    /// transaction on error. A coinbase elsewhere than first is left to `check_block`.
+    pub fn apply_block(
+        &self,
+        block: &Block,
+        height: u64,
+        subsidy: u64,
+    ) -> Result<State, (usize, TransactionError)> {
+        let mut state = self.clone();
+        let mut fees: u64 = 0;
+        for (i, tx) in block.content.data.iter().enumerate() {
+            if let BlockTransaction::Signed(tx) = tx {
+                let fee = state.apply_transaction(tx).map_err(|e| (i, e))?;
+                fees = fees.saturating_add(fee);
+            }
+        }
+        // paid last, so that the reward cannot be spent in its own block
+        if let Some(BlockTransaction::Coinbase(coinbase)) = block.content.data.first() {
+            super::check_coinbase(coinbase, height, subsidy.saturating_add(fees))
+                .and_then(|_| state.apply_coinbase(coinbase))
+                .map_err(|e| (0, e))?;
+        }
+        Ok(state)
+    }
+
+    /// Check that a pending transaction may apply on top of this state once the sender's pending
+    /// transactions with lower account nonces do: its account nonce is not used yet and at most
+    /// `MAX_NONCE_GAP` ahead, and the sender can afford the value and the fee.
+    pub fn check_pending(&self, tx: &SignedTransaction) -> Result<(), TransactionError> {
+        let fee = tx.fee();
+        let tx = &tx.transaction;
+        let (nonce, balance) = self.get(&tx.sender);
+        if tx.account_nonce <= nonce || tx.account_nonce - nonce > MAX_NONCE_GAP {
+            return Err(TransactionError::WrongNonce {
+                expected: nonce.wrapping_add(1),
+                actual: tx.account_nonce,
+            });
+        }
+        let value = u64::from(tx.value) + fee;
+        if balance < value {
+            return Err(TransactionError::InsufficientBalance { balance, value });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign_transaction, Transaction};
+
+    fn transfer(sender: Address, receiver: Address, value: u32, nonce: u32) -> SignedTransaction {
+        transfer_with_fee(sender, receiver, value, 0, nonce)
+    }
+
+    fn transfer_with_fee(
+        sender: Address,
+        receiver: Address,
+        value: u32,
+        fee: u32,
+        nonce: u32,
+    ) -> SignedTransaction {
+        let transaction = Transaction {
+            sender,
+            receiver,
+            value,
+            fee,
+            account_nonce: nonce,
+        };
+        sign_transaction(transaction, &key_pair::random())
+    }
+
+    #[test]
+    fn apply_transfers() {
+        let alice = generate_random_address();
+        let bob = generate_random_address();
+        let mut state = State::new();
+        state.credit(alice, 100);
+        state
+            .apply_transaction(&transfer(alice, bob, 30, 1))
+            .unwrap();
+        state
+            .apply_transaction(&transfer(alice, bob, 70, 2))
+            .unwrap();
+        assert_eq!(state.get(&alice), (2, 0));
+        assert_eq!(state.get(&bob), (0, 100));
+        assert_eq!(
+            state.check_pending(&transfer(alice, bob, 0, 2)),
+            Err(TransactionError::WrongNonce {
+                expected: 3,
+                actual: 2
+            })
+        );
+        assert_eq!(state.check_pending(&transfer(alice, bob, 0, 3)), Ok(()));
+    }
+
+    #[test]
+    fn reject_overdraft_and_replay() {
+        let alice = generate_random_address();
+        let bob = generate_random_address();
+        let mut state = State::new();
+        state.credit(alice, 10);
+        assert_eq!(
+            state.apply_transaction(&transfer(alice, bob, 11, 1)),
+            Err(TransactionError::InsufficientBalance {
+                balance: 10,
+                value: 11
+            })
+        );
+        let tx = transfer(alice, bob, 5, 1);
+        state.apply_transaction(&tx).unwrap();
+        assert_eq!(
+            state.apply_transaction(&tx),
+            Err(TransactionError::WrongNonce {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert_eq!(state.get(&alice), (1, 5));
+        assert_eq!(state.get(&bob), (0, 5));
+    }
+
+    #[test]
+    fn charge_fee() {
+        let alice = generate_random_address();
+        let bob = generate_random_address();
+        let mut state = State::new();
+        state.credit(alice, 100);
+        assert_eq!(
+            state.check_transaction(&transfer_with_fee(alice, bob, 95, 6, 1)),
+            Err(TransactionError::InsufficientBalance {
+                balance: 100,
+                value: 101
+            })
+        );
+        assert_eq!(
+            state.apply_transaction(&transfer_with_fee(alice, bob, 95, 5, 1)),
+            Ok(5)
+        );
+        assert_eq!(state.get(&alice), (1, 0));
+        assert_eq!(state.get(&bob), (0, 95));
+    }
+
+    #[test]
+    fn check_pending_transfers() {
+        let alice = generate_random_address();
+        let bob = generate_random_address();
+        let mut state = State::new();
+        state.credit(alice, 10);
+        // every pending transfer needs to be affordable on its own
+        assert_eq!(
+            state.check_pending(&transfer_with_fee(alice, bob, 10, 0, 2)),
+            Ok(())
+        );
+        assert_eq!(
+            state.check_pending(&transfer_with_fee(alice, bob, 10, 1, 1)),
+            Err(TransactionError::InsufficientBalance {
+                balance: 10,
+                value: 11
+            })
+        );
+        assert_eq!(
+            state.check_pending(&transfer(alice, bob, 1, MAX_NONCE_GAP)),
+            Ok(())
+        );
+        assert_eq!(
+            state.check_pending(&transfer(alice, bob, 1, MAX_NONCE_GAP + 1)),
+            Err(TransactionError::WrongNonce {
+                expected: 1,
+                actual: MAX_NONCE_GAP + 1
+            })
+        );
+        assert_eq!(
+            state.check_pending(&transfer(bob, alice, 1, 1)),
+            Err(TransactionError::InsufficientBalance {
+                balance: 0,
+                value: 1
+            })
+        );
+    }
+}
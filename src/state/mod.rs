@@ -0,0 +1,141 @@
+// The ledger state after a block. The transaction model is chosen at compile time: accounts with
+// nonces and balances by default, or unspent transaction outputs with the `utxo` feature.
+
+#[cfg(not(feature = "utxo"))]
+mod account;
+#[cfg(feature = "utxo")]
+mod utxo;
+
+#[cfg(not(feature = "utxo"))]
+pub use account::State;
+#[cfg(feature = "utxo")]
+pub use utxo::UtxoSet as State;
+
+use crate::types::transaction::Coinbase;
+#[cfg(feature = "utxo")]
+use crate::types::transaction::OutPoint;
+use std::fmt;
+
+/// The reason a transaction cannot be applied to a state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    /// The sender's balance is lower than the value plus the fee
+    #[cfg(not(feature = "utxo"))]
+    InsufficientBalance { balance: u64, value: u64 },
+    /// The account nonce is not one plus the sender's, e.g., the transaction is a replay
+    #[cfg(not(feature = "utxo"))]
+    WrongNonce { expected: u32, actual: u32 },
+    /// The receiver's balance would overflow
+    #[cfg(not(feature = "utxo"))]
+    BalanceOverflow,
+    /// The transaction spends nothing
+    #[cfg(feature = "utxo")]
+    NoInputs,
+    /// The input is not in the UTXO set, i.e., it does not exist or is already spent
+    #[cfg(feature = "utxo")]
+    MissingInput(OutPoint),
+    /// The input is spent twice in the same block or transaction
+    #[cfg(feature = "utxo")]
+    DoubleSpend(OutPoint),
+    /// The signature of the input at this index is missing or not by the owner of the output
+    #[cfg(feature = "utxo")]
+    WrongOwner(usize),
+    /// The outputs are worth more than the inputs
+    #[cfg(feature = "utxo")]
+    InsufficientInputs { input: u64, output: u64 },
+    /// The fee is not the difference between the input and output values
+    #[cfg(feature = "utxo")]
+    WrongFee { expected: u64, actual: u64 },
+    /// The sum of the input or output values overflows
+    #[cfg(feature = "utxo")]
+    ValueOverflow,
+    /// The coinbase is not for the height of its block
+    WrongCoinbaseHeight { expected: u64, actual: u64 },
+    /// The coinbase pays more than the block subsidy plus the fees
+    CoinbaseOverpays { allowed: u64, value: u64 },
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(not(feature = "utxo"))]
+            TransactionError::InsufficientBalance { balance, value } => {
+                write!(
+                    f,
+                    "balance {} is lower than value plus fee {}",
+                    balance, value
+                )
+            }
+            #[cfg(not(feature = "utxo"))]
+            TransactionError::WrongNonce { expected, actual } => {
+                write!(
+                    f,
+                    "account nonce {} is not the expected {}",
+                    actual, expected
+                )
+            }
+            #[cfg(not(feature = "utxo"))]
+            TransactionError::BalanceOverflow => write!(f, "receiver balance overflows"),
+            #[cfg(feature = "utxo")]
+            TransactionError::NoInputs => write!(f, "transaction has no inputs"),
+            #[cfg(feature = "utxo")]
+            TransactionError::MissingInput(outpoint) => {
+                write!(
+                    f,
+                    "input {}:{} is not unspent",
+                    outpoint.hash, outpoint.index
+                )
+            }
+            #[cfg(feature = "utxo")]
+            TransactionError::DoubleSpend(outpoint) => {
+                write!(
+                    f,
+                    "input {}:{} is spent twice",
+                    outpoint.hash, outpoint.index
+                )
+            }
+            #[cfg(feature = "utxo")]
+            TransactionError::WrongOwner(i) => write!(f, "input {} is not signed by its owner", i),
+            #[cfg(feature = "utxo")]
+            TransactionError::InsufficientInputs { input, output } => {
+                write!(f, "inputs {} are lower than outputs {}", input, output)
+            }
+            #[cfg(feature = "utxo")]
+            TransactionError::WrongFee { expected, actual } => {
+                write!(f, "fee {} is not the expected {}", actual, expected)
+            }
+            #[cfg(feature = "utxo")]
+            TransactionError::ValueOverflow => write!(f, "sum of values overflows"),
+            TransactionError::WrongCoinbaseHeight { expected, actual } => {
+                write!(
+                    f,
+                    "coinbase height {} is not the expected {}",
+                    actual, expected
+                )
+            }
+            TransactionError::CoinbaseOverpays { allowed, value } => {
+                write!(f, "coinbase value {} is more than {}", value, allowed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// Check that a coinbase is for the block at `height` and pays at most `allowed`, i.e., the block
+/// subsidy plus the fees of the block.
+fn check_coinbase(coinbase: &Coinbase, height: u64, allowed: u64) -> Result<(), TransactionError> {
+    if coinbase.height != height {
+        return Err(TransactionError::WrongCoinbaseHeight {
+            expected: height,
+            actual: coinbase.height,
+        });
+    }
+    if coinbase.value > allowed {
+        return Err(TransactionError::CoinbaseOverpays {
+            allowed,
+            value: coinbase.value,
+        });
+    }
+    Ok(())
+}
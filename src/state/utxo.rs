@@ -0,0 +1,306 @@
+use super::TransactionError;
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{BlockTransaction, Coinbase, OutPoint, Output, SignedTransaction};
+use ring::digest;
+use std::collections::{HashMap, HashSet};
+
+/// The ledger state in the UTXO model: every unspent output, keyed by its outpoint.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, Output>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Output> {
+        self.utxos.get(outpoint)
+    }
+
+    /// Get the sum of the unspent outputs of an address.
+    pub fn balance(&self, address: &Address) -> u64 {
+        self.utxos
+            .values()
+            .filter(|output| output.address == *address)
+            .fold(0, |sum, output| sum.saturating_add(output.value))
+    }
+
+    /// Create an output out of thin air, e.g., for the initial coin offering. Its outpoint hash is
+    /// derived from the address, the amount and the size of the set, so that it is deterministic.
+    pub fn credit(&mut self, address: Address, amount: u64) {
+        let preimage = bincode::serialize(&(address, amount, self.utxos.len() as u64)).unwrap();
+        let hash: H256 = digest::digest(&digest::SHA256, &preimage).into();
+        self.utxos.insert(
+            OutPoint { hash, index: 0 },
+            Output {
+                address,
+                value: amount,
+            },
+        );
+    }
+
+    /// Check that a transaction applies on top of this set: every input is unspent and signed by
+    /// the owner of the output, the inputs are worth at least the outputs and the fee is the
+    /// difference. Returns the fee.
+    pub fn check_transaction(&self, tx: &SignedTransaction) -> Result<u64, TransactionError> {
+        let inputs = &tx.transaction.inputs;
+        if inputs.is_empty() {
+            return Err(TransactionError::NoInputs);
+        }
+        let mut spent = HashSet::new();
+        let mut input: u64 = 0;
+        for (i, outpoint) in inputs.iter().enumerate() {
+            if !spent.insert(*outpoint) {
+                return Err(TransactionError::DoubleSpend(*outpoint));
+            }
+            let output = self
+                .utxos
+                .get(outpoint)
+                .ok_or(TransactionError::MissingInput(*outpoint))?;
+            match tx.signatures.get(i) {
+                Some(s) if Address::from_public_key_bytes(&s.public_key) == output.address => {}
+                _ => return Err(TransactionError::WrongOwner(i)),
+            }
+            input = input
+                .checked_add(output.value)
+                .ok_or(TransactionError::ValueOverflow)?;
+        }
+        let output = tx
+            .transaction
+            .outputs
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .ok_or(TransactionError::ValueOverflow)?;
+        if input < output {
+            return Err(TransactionError::InsufficientInputs { input, output });
+        }
+        if tx.fee() != input - output {
+            return Err(TransactionError::WrongFee {
+                expected: input - output,
+                actual: tx.fee(),
+            });
+        }
+        Ok(tx.fee())
+    }
+
+    /// Apply a transaction: spend its inputs and add its outputs. Returns the fee, and leaves the
+    /// set unchanged on error.
+    pub fn apply_transaction(&mut self, tx: &SignedTransaction) -> Result<u64, TransactionError> {
+        let fee = self.check_transaction(tx)?;
+        for outpoint in tx.transaction.inputs.iter() {
+            self.utxos.remove(outpoint);
+        }
+        let hash = tx.transaction.hash();
+        for (index, output) in tx.transaction.outputs.iter().enumerate() {
+            let outpoint = OutPoint {
+                hash,
+                index: index as u32,
+            };
+            self.utxos.insert(outpoint, output.clone());
+        }
+        Ok(fee)
+    }
+
+    /// Add the single output of a coinbase, at index 0 of the coinbase hash.
+    pub fn apply_coinbase(&mut self, coinbase: &Coinbase) -> Result<(), TransactionError> {
+        let outpoint = OutPoint {
+            hash: coinbase.hash(),
+            index: 0,
+        };
+        let output = Output {
+            address: coinbase.receiver,
+            value: coinbase.value,
+        };
+        self.utxos.insert(outpoint, output);
+        Ok(())
+    }
+
+    /// Apply the transactions of the block at `height` in order, then its coinbase, which may pay
+    /// up to `subsidy` plus the fees. Returns the resulting set, or the index of the first failing
+    /// transaction on error. An output spent by two transactions of the block is a double spend
+    /// rather than a missing input. A coinbase elsewhere than first is left to `check_block`.
+    pub fn apply_block(
+        &self,
+        block: &Block,
+        height: u64,
+        subsidy: u64,
+    ) -> Result<UtxoSet, (usize, TransactionError)> {
+        let mut state = self.clone();
+        let mut spent = HashSet::new();
+        let mut fees: u64 = 0;
+        for (i, tx) in block.content.data.iter().enumerate() {
+            let tx = match tx {
+                BlockTransaction::Signed(tx) => tx,
+                BlockTransaction::Coinbase(_) => continue,
+            };
+            for outpoint in tx.transaction.inputs.iter() {
+                if spent.contains(outpoint) {
+                    return Err((i, TransactionError::DoubleSpend(*outpoint)));
+                }
+            }
+            let fee = state.apply_transaction(tx).map_err(|e| (i, e))?;
+            fees = fees.saturating_add(fee);
+            spent.extend(tx.transaction.inputs.iter().cloned());
+        }
+        // paid last, so that the reward cannot be spent in its own block
+        if let Some(BlockTransaction::Coinbase(coinbase)) = block.content.data.first() {
+            super::check_coinbase(coinbase, height, subsidy.saturating_add(fees))
+                .and_then(|_| state.apply_coinbase(coinbase))
+                .map_err(|e| (0, e))?;
+        }
+        Ok(state)
+    }
+
+    /// Check that a pending transaction may apply on top of this set, i.e., it applies now. An
+    /// output created by a transaction that is still pending cannot be spent until that one is in
+    /// the chain.
+    pub fn check_pending(&self, tx: &SignedTransaction) -> Result<(), TransactionError> {
+        self.check_transaction(tx).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+    use crate::types::block::generate_block_with;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign_transaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn spend(
+        key: &Ed25519KeyPair,
+        inputs: Vec<OutPoint>,
+        outputs: Vec<(Address, u64)>,
+        fee: u64,
+    ) -> SignedTransaction {
+        let outputs = outputs
+            .into_iter()
+            .map(|(address, value)| Output { address, value })
+            .collect();
+        let transaction = Transaction {
+            inputs,
+            outputs,
+            fee,
+        };
+        sign_transaction(transaction, key)
+    }
+
+    /// A set with a single output of `value` owned by `key`.
+    fn funded(key: &Ed25519KeyPair, value: u64) -> (UtxoSet, OutPoint) {
+        let mut state = UtxoSet::new();
+        state.credit(Address::from_public_key(*key.public_key()), value);
+        let outpoint = *state.utxos.keys().next().unwrap();
+        (state, outpoint)
+    }
+
+    #[test]
+    fn apply_transfers() {
+        let alice = key_pair::random();
+        let bob = generate_random_address();
+        let (mut state, coin) = funded(&alice, 100);
+        let tx = spend(&alice, vec![coin], vec![(bob, 30), (bob, 60)], 10);
+        state.apply_transaction(&tx).unwrap();
+        assert_eq!(state.balance(&bob), 90);
+        assert_eq!(state.get(&coin), None);
+        assert_eq!(
+            state.check_pending(&tx),
+            Err(TransactionError::MissingInput(coin))
+        );
+        let change = OutPoint {
+            hash: tx.transaction.hash(),
+            index: 1,
+        };
+        assert_eq!(state.get(&change).unwrap().value, 60);
+    }
+
+    #[test]
+    fn reject_invalid_spends() {
+        let alice = key_pair::random();
+        let bob = generate_random_address();
+        let (state, coin) = funded(&alice, 100);
+        let missing = OutPoint {
+            hash: generate_random_hash(),
+            index: 0,
+        };
+        assert_eq!(
+            state.check_transaction(&spend(&alice, vec![missing], vec![], 0)),
+            Err(TransactionError::MissingInput(missing))
+        );
+        assert_eq!(
+            state.check_transaction(&spend(&alice, vec![coin], vec![(bob, 101)], 0)),
+            Err(TransactionError::InsufficientInputs {
+                input: 100,
+                output: 101
+            })
+        );
+        assert_eq!(
+            state.check_transaction(&spend(&key_pair::random(), vec![coin], vec![(bob, 1)], 99)),
+            Err(TransactionError::WrongOwner(0))
+        );
+        assert_eq!(
+            state.check_transaction(&spend(&alice, vec![coin, coin], vec![], 0)),
+            Err(TransactionError::DoubleSpend(coin))
+        );
+        assert_eq!(
+            state.check_transaction(&spend(&alice, vec![coin], vec![(bob, 90)], 0)),
+            Err(TransactionError::WrongFee {
+                expected: 10,
+                actual: 0
+            })
+        );
+
+        let first = spend(&alice, vec![coin], vec![(bob, 100)], 0);
+        let second = spend(&alice, vec![coin], vec![(bob, 50)], 50);
+        let block = generate_block_with(&generate_random_hash(), vec![first, second]);
+        assert_eq!(
+            state.apply_block(&block, 1, 0),
+            Err((1, TransactionError::DoubleSpend(coin)))
+        );
+    }
+
+    #[test]
+    fn coinbase_claims_fees() {
+        let alice = key_pair::random();
+        let bob = generate_random_address();
+        let miner = generate_random_address();
+        let (state, coin) = funded(&alice, 100);
+        let tx = spend(&alice, vec![coin], vec![(bob, 90)], 10);
+        assert_eq!(state.check_transaction(&tx), Ok(10));
+        let mut coinbase = Coinbase {
+            height: 1,
+            receiver: miner,
+            value: 15,
+        };
+        let mut block = generate_block_with(&generate_random_hash(), vec![tx]);
+        block
+            .content
+            .data
+            .insert(0, BlockTransaction::Coinbase(coinbase.clone()));
+        let after = state.apply_block(&block, 1, 5).unwrap();
+        assert_eq!(after.balance(&miner), 15);
+        let reward = OutPoint {
+            hash: coinbase.hash(),
+            index: 0,
+        };
+        assert_eq!(after.get(&reward).unwrap().value, 15);
+
+        coinbase.value = 16;
+        block.content.data[0] = BlockTransaction::Coinbase(coinbase);
+        assert_eq!(
+            state.apply_block(&block, 1, 5),
+            Err((
+                0,
+                TransactionError::CoinbaseOverpays {
+                    allowed: 15,
+                    value: 16
+                }
+            ))
+        );
+    }
+}
@@ -3,7 +3,7 @@ use ring::{digest, signature::{Ed25519KeyPair, KeyPair}};
 use serde::{Deserialize, Serialize};
 
 // 20-byte address
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct Address([u8; 20]);
 
 impl std::convert::From<&[u8; 20]> for Address {
@@ -38,6 +38,17 @@ impl std::fmt::Display for Address {
     }
 }
 
+/// Parse an address from its 40 hex digits, as printed by `Display`.
+impl std::str::FromStr for Address {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Address, Self::Err> {
+        let mut bytes = [0u8; 20];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(bytes.into())
+    }
+}
+
 impl std::fmt::Debug for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -1,26 +1,137 @@
 use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::BlockTransaction;
+use ring::digest;
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// The block header. Only the header is hashed, so it commits to the content through
+/// `merkle_root`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Block {}
+pub struct Header {
+    pub parent: H256,
+    pub nonce: u32,
+    pub difficulty: H256,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u128,
+    pub merkle_root: H256,
+}
+
+/// The transactions carried by a block, starting with the coinbase if there is one.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Content {
+    pub data: Vec<BlockTransaction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub header: Header,
+    pub content: Content,
+}
+
+impl Hashable for Header {
+    fn hash(&self) -> H256 {
+        let s = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, s.as_ref()).into()
+    }
+}
+
+impl Content {
+    /// Merkle root of the transactions.
+    pub fn merkle_root(&self) -> H256 {
+        MerkleTree::new(&self.data).root()
+    }
+}
 
 impl Hashable for Block {
     fn hash(&self) -> H256 {
-        unimplemented!()
+        self.header.hash()
     }
 }
 
 impl Block {
+    /// Create a block with nonce 0 whose Merkle root is computed from `content`.
+    pub fn new(parent: H256, difficulty: H256, timestamp: u128, content: Content) -> Self {
+        let merkle_root = content.merkle_root();
+        Block {
+            header: Header {
+                parent,
+                nonce: 0,
+                difficulty,
+                timestamp,
+                merkle_root,
+            },
+            content,
+        }
+    }
+
     pub fn get_parent(&self) -> H256 {
-        unimplemented!()
+        self.header.parent
     }
 
     pub fn get_difficulty(&self) -> H256 {
-        unimplemented!()
+        self.header.difficulty
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
     }
 }
 
-#[cfg(any(test, test_utilities))]
+/// Current time in milliseconds since the unix epoch, the unit of `Header::timestamp`.
+pub fn current_timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+#[cfg(any(test, feature = "test-utilities"))]
 pub fn generate_random_block(parent: &H256) -> Block {
-    unimplemented!()
+    use rand::Rng;
+    let mut block = Block::new(
+        *parent,
+        [255u8; 32].into(),
+        current_timestamp(),
+        Content::default(),
+    );
+    block.header.nonce = rand::thread_rng().gen();
+    block
+}
+
+/// Generate a random block on top of `parent` holding the given transactions.
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn generate_block_with(
+    parent: &H256,
+    data: Vec<crate::types::transaction::SignedTransaction>,
+) -> Block {
+    let mut block = generate_random_block(parent);
+    block.content = Content {
+        data: data.into_iter().map(BlockTransaction::from).collect(),
+    };
+    block.header.merkle_root = block.content.merkle_root();
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::transaction::SignedTransaction;
+
+    #[test]
+    fn hash_covers_header_only() {
+        let block = generate_random_block(&generate_random_hash());
+        let mut other = block.clone();
+        other.content.data.push(SignedTransaction::default().into());
+        assert_eq!(block.hash(), other.hash());
+        other.header.nonce = other.header.nonce.wrapping_add(1);
+        assert_ne!(block.hash(), other.hash());
+    }
+
+    #[test]
+    fn empty_content_merkle_root() {
+        let block = generate_random_block(&generate_random_hash());
+        assert_eq!(block.header.merkle_root, H256::default());
+    }
 }
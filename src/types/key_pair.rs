@@ -1,9 +1,98 @@
+use ring::digest;
+use ring::error::KeyRejected;
 use ring::rand;
-use ring::signature::Ed25519KeyPair;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+/// The PKCS#8 v2 encoding of an Ed25519 key pair, as generated by ring, is this prefix, the
+/// 32-byte seed, this separator and the 32-byte public key.
+const PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x53, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+const PKCS8_SEPARATOR: [u8; 5] = [0xa1, 0x23, 0x03, 0x21, 0x00];
 
 /// Generate a random key pair.
+#[allow(clippy::useless_conversion)]
 pub fn random() -> Ed25519KeyPair {
     let rng = rand::SystemRandom::new();
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Generate a random key pair as PKCS#8 bytes, to be saved and imported with `from_pkcs8`.
+pub fn generate_pkcs8() -> Vec<u8> {
+    let rng = rand::SystemRandom::new();
+    Ed25519KeyPair::generate_pkcs8(&rng)
+        .unwrap()
+        .as_ref()
+        .to_vec()
+}
+
+/// Import a key pair from PKCS#8 v1 or v2 bytes, e.g., from `generate_pkcs8` or `to_pkcs8`.
+pub fn from_pkcs8(bytes: &[u8]) -> Result<Ed25519KeyPair, KeyRejected> {
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(bytes)
+}
+
+/// Export the key pair of a seed as PKCS#8 v2 bytes. ring cannot export a key pair it already
+/// parsed, so the seed is needed.
+pub fn to_pkcs8(seed: &[u8; 32]) -> Vec<u8> {
+    let key = from_seed(seed);
+    [
+        &PKCS8_PREFIX[..],
+        seed,
+        &PKCS8_SEPARATOR,
+        key.public_key().as_ref(),
+    ]
+    .concat()
+}
+
+/// Derive a key pair from a 32-byte seed, i.e., the Ed25519 private key.
+pub fn from_seed(seed: &[u8; 32]) -> Ed25519KeyPair {
+    Ed25519KeyPair::from_seed_unchecked(seed).unwrap()
+}
+
+/// The seed of the key pair number `index`: the SHA256 hash of the big-endian index.
+pub fn index_seed(index: u64) -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    seed.copy_from_slice(digest::digest(&digest::SHA256, &index.to_be_bytes()).as_ref());
+    seed
+}
+
+/// Derive the key pair number `index` deterministically, so that every node agrees on it, e.g.,
+/// for the accounts funded at genesis.
+pub fn from_index(index: u64) -> Ed25519KeyPair {
+    from_seed(&index_seed(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_from_seed() {
+        let seed = [7u8; 32];
+        assert_eq!(
+            from_seed(&seed).public_key().as_ref(),
+            from_seed(&seed).public_key().as_ref()
+        );
+        assert_eq!(
+            from_index(1).public_key().as_ref(),
+            from_seed(&index_seed(1)).public_key().as_ref()
+        );
+        assert_ne!(
+            from_index(1).public_key().as_ref(),
+            from_index(2).public_key().as_ref()
+        );
+    }
+
+    #[test]
+    fn pkcs8_round_trip() {
+        let pkcs8 = generate_pkcs8();
+        let key = from_pkcs8(&pkcs8).unwrap();
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&pkcs8[PKCS8_PREFIX.len()..PKCS8_PREFIX.len() + 32]);
+        assert_eq!(to_pkcs8(&seed), pkcs8);
+        let imported = from_pkcs8(&to_pkcs8(&seed)).unwrap();
+        assert_eq!(imported.public_key().as_ref(), key.public_key().as_ref());
+        assert!(from_pkcs8(&pkcs8[1..]).is_err());
+    }
+}
@@ -2,5 +2,13 @@ pub mod address;
 pub mod block;
 pub mod hash;
 pub mod key_pair;
+// kept as written, `is_multiple_of` would need Rust 1.87
+#[allow(
+    clippy::assign_op_pattern,
+    clippy::into_iter_on_ref,
+    clippy::manual_is_multiple_of,
+    clippy::needless_range_loop
+)]
 pub mod merkle;
 pub mod transaction;
+pub mod u256;
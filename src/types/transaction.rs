@@ -1,18 +1,51 @@
-use rand::Rng;
 use ring::{
     digest,
     signature::{self, Ed25519KeyPair, Signature},
 };
 use serde::{Deserialize, Serialize};
 
-use crate::types::address::{generate_random_address, Address};
+use crate::types::address::Address;
 use crate::types::hash::{Hashable, H256};
 
+/// A transfer in the account model, applied to the sender's and receiver's balances.
+#[cfg(not(feature = "utxo"))]
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
-    sender: Address,
-    receiver: Address,
-    value: u32,
+    pub sender: Address,
+    pub receiver: Address,
+    pub value: u32,
+    /// Paid by the sender on top of the value, to the miner of the block.
+    pub fee: u32,
+    /// Must be one plus the sender's account nonce, so that every transaction applies only once.
+    pub account_nonce: u32,
+}
+
+/// A reference to an output of a transaction, by the hash of the (unsigned) transaction and the
+/// index of the output.
+#[cfg(feature = "utxo")]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub hash: H256,
+    pub index: u32,
+}
+
+#[cfg(feature = "utxo")]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub address: Address,
+    pub value: u64,
+}
+
+/// A transfer in the UTXO model, spending whole outputs of earlier transactions and creating new
+/// ones.
+#[cfg(feature = "utxo")]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Transaction {
+    pub inputs: Vec<OutPoint>,
+    pub outputs: Vec<Output>,
+    /// Paid to the miner of the block. Must be exactly the difference between the input and
+    /// output values, so that it is known without the UTXO set.
+    pub fee: u64,
 }
 
 impl Hashable for Transaction {
@@ -22,6 +55,7 @@ impl Hashable for Transaction {
     }
 }
 
+#[cfg(not(feature = "utxo"))]
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
     pub transaction: Transaction,
@@ -29,6 +63,22 @@ pub struct SignedTransaction {
     pub public_key: Vec<u8>,
 }
 
+/// The signature of the transaction by the owner of one of its inputs.
+#[cfg(feature = "utxo")]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct InputSignature {
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+#[cfg(feature = "utxo")]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SignedTransaction {
+    pub transaction: Transaction,
+    /// One signature per input, in the same order
+    pub signatures: Vec<InputSignature>,
+}
+
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
         let s = bincode::serialize(&self).unwrap();
@@ -36,6 +86,85 @@ impl Hashable for SignedTransaction {
     }
 }
 
+/// The first transaction of a block, which creates the block reward out of thin air and pays it
+/// to the miner. It is not signed, and never enters the mempool.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Coinbase {
+    /// Height of the block, so that the coinbases of different blocks have different hashes
+    pub height: u64,
+    pub receiver: Address,
+    /// At most the block subsidy plus the fees of the other transactions of the block
+    pub value: u64,
+}
+
+impl Hashable for Coinbase {
+    fn hash(&self) -> H256 {
+        let s = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, s.as_ref()).into()
+    }
+}
+
+/// A transaction carried by a block: the coinbase, only valid as the first one, or a signed
+/// transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BlockTransaction {
+    Coinbase(Coinbase),
+    Signed(SignedTransaction),
+}
+
+/// The hash of the coinbase or of the signed transaction, so that a transaction has the same
+/// hash in the mempool and in a block.
+impl Hashable for BlockTransaction {
+    fn hash(&self) -> H256 {
+        match self {
+            BlockTransaction::Coinbase(coinbase) => coinbase.hash(),
+            BlockTransaction::Signed(tx) => tx.hash(),
+        }
+    }
+}
+
+impl From<SignedTransaction> for BlockTransaction {
+    fn from(tx: SignedTransaction) -> Self {
+        BlockTransaction::Signed(tx)
+    }
+}
+
+impl SignedTransaction {
+    #[cfg(not(feature = "utxo"))]
+    pub fn fee(&self) -> u64 {
+        u64::from(self.transaction.fee)
+    }
+
+    #[cfg(feature = "utxo")]
+    pub fn fee(&self) -> u64 {
+        self.transaction.fee
+    }
+
+    /// Size of the serialized transaction in bytes, i.e., how much of a block it takes.
+    pub fn size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
+
+    /// Verify the signature of the transaction, and that the public key is the sender's, i.e.,
+    /// only the owner of an account can spend from it.
+    #[cfg(not(feature = "utxo"))]
+    pub fn verify(&self) -> bool {
+        Address::from_public_key_bytes(&self.public_key) == self.transaction.sender
+            && verify(&self.transaction, &self.public_key, &self.signature)
+    }
+
+    /// Verify that there is one signature per input and that every signature is valid. Whether
+    /// the signers own the inputs depends on the UTXO set.
+    #[cfg(feature = "utxo")]
+    pub fn verify(&self) -> bool {
+        self.signatures.len() == self.transaction.inputs.len()
+            && self
+                .signatures
+                .iter()
+                .all(|s| verify(&self.transaction, &s.public_key, &s.signature))
+    }
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     let t = bincode::serialize(&t).unwrap();
@@ -51,12 +180,78 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     pub_key.verify(&hash, signature).is_ok()
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(all(any(test, feature = "test-utilities"), not(feature = "utxo")))]
 pub fn generate_random_transaction() -> Transaction {
-    Transaction { 
-        sender: generate_random_address(), 
-        receiver: generate_random_address(), 
+    use crate::types::address::generate_random_address;
+    // the first transaction of a fresh account, valid in any state that has not seen it
+    Transaction {
+        sender: generate_random_address(),
+        receiver: generate_random_address(),
         value: 0,
+        fee: 0,
+        account_nonce: 1,
+    }
+}
+
+#[cfg(all(any(test, feature = "test-utilities"), feature = "utxo"))]
+pub fn generate_random_transaction() -> Transaction {
+    use crate::types::address::generate_random_address;
+    use crate::types::hash::generate_random_hash;
+    Transaction {
+        inputs: vec![OutPoint {
+            hash: generate_random_hash(),
+            index: 0,
+        }],
+        outputs: vec![Output {
+            address: generate_random_address(),
+            value: 0,
+        }],
+        fee: 0,
+    }
+}
+
+/// Sign a random transaction with a fresh key pair, which owns the sender in the account model.
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn generate_random_signed_transaction() -> SignedTransaction {
+    use crate::types::key_pair;
+    #[allow(unused_mut)]
+    let mut transaction = generate_random_transaction();
+    let key = key_pair::random();
+    #[cfg(not(feature = "utxo"))]
+    {
+        use ring::signature::KeyPair;
+        transaction.sender = Address::from_public_key(*key.public_key());
+    }
+    sign_transaction(transaction, &key)
+}
+
+/// Sign a transaction with a key pair, once per input in the UTXO model.
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn sign_transaction(transaction: Transaction, key: &Ed25519KeyPair) -> SignedTransaction {
+    use ring::signature::KeyPair;
+    let signature = sign(&transaction, key).as_ref().to_vec();
+    let public_key = key.public_key().as_ref().to_vec();
+    #[cfg(not(feature = "utxo"))]
+    {
+        SignedTransaction {
+            transaction,
+            signature,
+            public_key,
+        }
+    }
+    #[cfg(feature = "utxo")]
+    {
+        let signatures = vec![
+            InputSignature {
+                signature,
+                public_key,
+            };
+            transaction.inputs.len()
+        ];
+        SignedTransaction {
+            transaction,
+            signatures,
+        }
     }
 }
 
@@ -85,6 +280,22 @@ mod tests {
         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
     }
+    #[cfg(not(feature = "utxo"))]
+    #[test]
+    fn verify_binds_sender() {
+        let tx = generate_random_signed_transaction();
+        assert!(tx.verify());
+        // a valid signature by someone else than the sender
+        let mut stolen = tx.clone();
+        stolen.transaction.sender = crate::types::address::generate_random_address();
+        let stolen = sign_transaction(stolen.transaction, &key_pair::random());
+        assert!(!stolen.verify());
+        assert!(verify(
+            &stolen.transaction,
+            &stolen.public_key,
+            &stolen.signature
+        ));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
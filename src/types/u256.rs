@@ -0,0 +1,437 @@
+use crate::types::hash::H256;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::ops::{Add, Div, Mul, Not, Rem, Shl, Shr, Sub};
+
+/// A 256-bit unsigned integer, for arithmetic on targets and chain work. The operators panic on
+/// overflow, division by zero and shifts of 256 bits or more, while the `checked_` methods return
+/// `None` instead.
+#[derive(Eq, PartialEq, Clone, Copy, Hash, Default)]
+pub struct U256([u64; 4]); // little endian limbs
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn is_zero(&self) -> bool {
+        *self == U256::ZERO
+    }
+
+    /// Number of significant bits, i.e., 0 for zero and 256 for `MAX`.
+    pub fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    /// The lowest 64 bits, discarding the rest.
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    pub fn overflowing_add(self, rhs: U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(rhs.0[i]);
+            let (sum, c2) = sum.overflowing_add(u64::from(carry));
+            *limb = sum;
+            carry = c1 || c2;
+        }
+        (U256(result), carry)
+    }
+
+    pub fn overflowing_sub(self, rhs: U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let (difference, b1) = self.0[i].overflowing_sub(rhs.0[i]);
+            let (difference, b2) = difference.overflowing_sub(u64::from(borrow));
+            *limb = difference;
+            borrow = b1 || b2;
+        }
+        (U256(result), borrow)
+    }
+
+    pub fn checked_add(self, rhs: U256) -> Option<U256> {
+        match self.overflowing_add(rhs) {
+            (sum, false) => Some(sum),
+            (_, true) => None,
+        }
+    }
+
+    pub fn saturating_add(self, rhs: U256) -> U256 {
+        self.checked_add(rhs).unwrap_or(U256::MAX)
+    }
+
+    pub fn checked_sub(self, rhs: U256) -> Option<U256> {
+        match self.overflowing_sub(rhs) {
+            (difference, false) => Some(difference),
+            (_, true) => None,
+        }
+    }
+
+    pub fn checked_mul(self, rhs: U256) -> Option<U256> {
+        // schoolbook multiplication into eight limbs, of which the upper four must stay zero
+        let mut product = [0u64; 8];
+        for i in 0..4 {
+            let mut carry = 0u128;
+            for j in 0..4 {
+                let t = u128::from(self.0[i]) * u128::from(rhs.0[j])
+                    + u128::from(product[i + j])
+                    + carry;
+                product[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            product[i + 4] = carry as u64;
+        }
+        if product[4..].iter().any(|&limb| limb != 0) {
+            return None;
+        }
+        Some(U256(product[..4].try_into().unwrap()))
+    }
+
+    /// Quotient and remainder, or `None` if `rhs` is zero.
+    pub fn checked_div_rem(self, rhs: U256) -> Option<(U256, U256)> {
+        if rhs.is_zero() {
+            return None;
+        }
+        // binary long division over the significant bits of the dividend
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for i in (0..self.bits()).rev() {
+            remainder = remainder << 1;
+            remainder.0[0] |= self.bit(i);
+            if remainder >= rhs {
+                remainder = remainder - rhs;
+                quotient.0[i as usize / 64] |= 1 << (i % 64);
+            }
+        }
+        Some((quotient, remainder))
+    }
+
+    pub fn checked_div(self, rhs: U256) -> Option<U256> {
+        self.checked_div_rem(rhs).map(|(quotient, _)| quotient)
+    }
+
+    pub fn checked_rem(self, rhs: U256) -> Option<U256> {
+        self.checked_div_rem(rhs).map(|(_, remainder)| remainder)
+    }
+
+    /// Shift left, discarding the bits shifted out, or `None` if `shift` is 256 or more.
+    pub fn checked_shl(self, shift: u32) -> Option<U256> {
+        if shift >= 256 {
+            return None;
+        }
+        let (limbs, bits) = ((shift / 64) as usize, shift % 64);
+        let mut result = [0u64; 4];
+        for (i, limb) in result.iter_mut().enumerate().skip(limbs) {
+            *limb = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                *limb |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        Some(U256(result))
+    }
+
+    /// Shift right, discarding the bits shifted out, or `None` if `shift` is 256 or more.
+    pub fn checked_shr(self, shift: u32) -> Option<U256> {
+        if shift >= 256 {
+            return None;
+        }
+        let (limbs, bits) = ((shift / 64) as usize, shift % 64);
+        let mut result = [0u64; 4];
+        for (i, limb) in result.iter_mut().enumerate().take(4 - limbs) {
+            *limb = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs < 3 {
+                *limb |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        Some(U256(result))
+    }
+
+    fn bit(&self, i: u32) -> u64 {
+        (self.0[i as usize / 64] >> (i % 64)) & 1
+    }
+
+    /// Decode the compact "nBits" form of Bitcoin headers: the top byte is the length in bytes,
+    /// and the lower three bytes are the most significant ones, of which the top bit is a sign.
+    /// Returns `None` for negative numbers and numbers that do not fit in 256 bits.
+    pub fn from_compact(compact: u32) -> Option<U256> {
+        let size = compact >> 24;
+        let mantissa = compact & 0x007f_ffff;
+        if mantissa == 0 {
+            return Some(U256::ZERO);
+        }
+        if compact & 0x0080_0000 != 0 {
+            return None;
+        }
+        if size <= 3 {
+            return Some(U256::from(u64::from(mantissa >> (8 * (3 - size)))));
+        }
+        let shift = 8 * (size - 3);
+        if shift + (32 - mantissa.leading_zeros()) > 256 {
+            return None;
+        }
+        Some(U256::from(u64::from(mantissa)) << shift)
+    }
+
+    /// Encode in the compact "nBits" form, keeping only the three most significant bytes, i.e.,
+    /// rounding down.
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut mantissa = if size <= 3 {
+            (self.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (*self >> (8 * (size - 3))).low_u64() as u32
+        };
+        // the top bit of the mantissa is the sign, so move a set one to the next byte
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        size << 24 | mantissa
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, rhs: U256) -> U256 {
+        self.checked_add(rhs).expect("attempt to add with overflow")
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, rhs: U256) -> U256 {
+        self.checked_sub(rhs)
+            .expect("attempt to subtract with overflow")
+    }
+}
+
+impl Mul for U256 {
+    type Output = U256;
+
+    fn mul(self, rhs: U256) -> U256 {
+        self.checked_mul(rhs)
+            .expect("attempt to multiply with overflow")
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    fn div(self, rhs: U256) -> U256 {
+        self.checked_div(rhs).expect("attempt to divide by zero")
+    }
+}
+
+impl Rem for U256 {
+    type Output = U256;
+
+    fn rem(self, rhs: U256) -> U256 {
+        self.checked_rem(rhs)
+            .expect("attempt to calculate the remainder with a divisor of zero")
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        self.checked_shl(shift)
+            .expect("attempt to shift left with overflow")
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        self.checked_shr(shift)
+            .expect("attempt to shift right with overflow")
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
+impl std::convert::From<u64> for U256 {
+    fn from(input: u64) -> U256 {
+        U256([input, 0, 0, 0])
+    }
+}
+
+impl std::convert::From<u128> for U256 {
+    fn from(input: u128) -> U256 {
+        U256([input as u64, (input >> 64) as u64, 0, 0])
+    }
+}
+
+/// Read a hash as a big endian integer.
+impl std::convert::From<&H256> for U256 {
+    fn from(input: &H256) -> U256 {
+        let bytes = input.as_ref();
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let start = 32 - 8 * (i + 1);
+            *limb = u64::from_be_bytes(bytes[start..start + 8].try_into().unwrap());
+        }
+        U256(limbs)
+    }
+}
+
+impl std::convert::From<H256> for U256 {
+    fn from(input: H256) -> U256 {
+        U256::from(&input)
+    }
+}
+
+impl std::convert::From<U256> for H256 {
+    fn from(input: U256) -> H256 {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in input.0.iter().enumerate() {
+            let start = 32 - 8 * (i + 1);
+            bytes[start..start + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes.into()
+    }
+}
+
+/// The 64 hex digits of the big endian bytes, as for `H256`.
+impl std::fmt::Display for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", H256::from(*self))
+    }
+}
+
+impl std::fmt::Debug for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+    use num_bigint::BigUint;
+    use rand::Rng;
+
+    /// Random numbers of every size, so that carries, borrows and overflows all happen.
+    fn random_u256() -> U256 {
+        let mut rng = rand::thread_rng();
+        let value = U256::from(generate_random_hash());
+        match rng.gen_range(0..4) {
+            0 => value >> rng.gen_range(0..256),
+            1 => value >> rng.gen_range(192..256),
+            2 => U256::MAX >> rng.gen_range(0..256),
+            _ => value,
+        }
+    }
+
+    fn big(value: U256) -> BigUint {
+        BigUint::from_bytes_be(H256::from(value).as_ref())
+    }
+
+    /// The reference result if it fits in 256 bits.
+    fn fit(value: BigUint) -> Option<U256> {
+        if value.bits() > 256 {
+            return None;
+        }
+        let bytes = value.to_bytes_be();
+        let mut padded = [0u8; 32];
+        padded[32 - bytes.len()..].copy_from_slice(&bytes);
+        Some(H256::from(padded).into())
+    }
+
+    #[test]
+    fn arithmetic_matches_reference() {
+        for _ in 0..2000 {
+            let (a, b) = (random_u256(), random_u256());
+            assert_eq!(a.checked_add(b), fit(big(a) + big(b)), "{} + {}", a, b);
+            let difference = if a >= b { fit(big(a) - big(b)) } else { None };
+            assert_eq!(a.checked_sub(b), difference, "{} - {}", a, b);
+            assert_eq!(a.checked_mul(b), fit(big(a) * big(b)), "{} * {}", a, b);
+            if b.is_zero() {
+                assert_eq!(a.checked_div_rem(b), None);
+            } else {
+                let expected = (fit(big(a) / big(b)).unwrap(), fit(big(a) % big(b)).unwrap());
+                assert_eq!(a.checked_div_rem(b), Some(expected), "{} / {}", a, b);
+            }
+            let shift = rand::thread_rng().gen_range(0..256);
+            let truncated = big(a) << shift as usize & big(U256::MAX);
+            assert_eq!(a << shift, fit(truncated).unwrap(), "{} << {}", a, shift);
+            assert_eq!(a >> shift, fit(big(a) >> shift as usize).unwrap());
+            assert_eq!(a.cmp(&b), big(a).cmp(&big(b)));
+            assert_eq!(u64::from(a.bits()), big(a).bits());
+        }
+    }
+
+    #[test]
+    fn edge_cases() {
+        assert_eq!(U256::MAX.checked_add(U256::ONE), None);
+        assert_eq!(U256::MAX.saturating_add(U256::ONE), U256::MAX);
+        assert_eq!(U256::ZERO.checked_sub(U256::ONE), None);
+        assert_eq!((U256::ONE << 128).checked_mul(U256::ONE << 128), None);
+        assert_eq!(U256::ONE.checked_shl(256), None);
+        assert_eq!(U256::MAX.checked_shr(256), None);
+        assert_eq!(!U256::ZERO, U256::MAX);
+        assert_eq!(U256::from(u128::MAX) + U256::ONE, U256::ONE << 128);
+        let hash = generate_random_hash();
+        assert_eq!(H256::from(U256::from(hash)), hash);
+        assert_eq!(
+            U256::from(hash).cmp(&U256::from(H256::default())),
+            hash.cmp(&H256::default())
+        );
+    }
+
+    #[test]
+    fn compact_encoding() {
+        // the Bitcoin genesis block target
+        let target = U256::from(0xffffu64) << 208;
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+        assert_eq!(U256::from_compact(0x1d00ffff), Some(target));
+        assert_eq!(U256::from(0x80u64).to_compact(), 0x02008000);
+        assert_eq!(U256::from_compact(0x01123456), Some(U256::from(0x12u64)));
+        assert_eq!(U256::from_compact(0x04923456), None);
+        assert_eq!(
+            U256::from_compact(0x2100ffff),
+            Some(U256::from(0xffffu64) << 240)
+        );
+        assert_eq!(U256::from_compact(0x2101ffff), None);
+        assert_eq!(U256::from_compact(0xff000000), Some(U256::ZERO));
+        for _ in 0..2000 {
+            let value = random_u256();
+            let rounded = U256::from_compact(value.to_compact()).unwrap();
+            // only the three most significant bytes are kept
+            assert!(rounded <= value);
+            let dropped = 8 * value.bits().div_ceil(8).saturating_sub(2);
+            assert!(big(value) - big(rounded) < BigUint::from(1u8) << dropped as usize);
+            assert_eq!(rounded.to_compact(), value.to_compact());
+        }
+    }
+}